@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 use clap::Parser;
+use santa_lang::ir::{CELL_BITS, Int, Port};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -11,13 +12,91 @@ pub struct Args {
     #[arg(long, help = "Ouput translation and execution trace.")]
     pub trace: bool,
 
+    #[arg(
+        long,
+        help = "Step through the program with commands from stdin: step [N], continue, stack ELF, watch ELF top, watch ELF sleeve N, quit."
+    )]
+    pub debug: bool,
+
+    #[arg(long, help = "Stop the program after this many steps.")]
+    pub max_steps: Option<u64>,
+
+    #[arg(
+        long,
+        default_value_t = 64,
+        value_name = "BITS",
+        help = "Width of a sheet: 8, 16, 32 or 64 bits. Values wrap around."
+    )]
+    pub cell_bits: u32,
+
+    #[arg(
+        long,
+        value_name = "SHEETS",
+        help = "Let at most this many sheets wait in a pipe between elves, the writer waits for the rest."
+    )]
+    pub pipe_capacity: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Seed the random numbers, so that a run can be repeated. Seeded from the clock otherwise."
+    )]
+    pub seed: Option<u64>,
+
+    #[arg(
+        long = "set",
+        value_name = "ELF.PORT=VALUE",
+        value_parser = parse_preset,
+        help = "Send a number to the elf's port when it is set up. Can be repeated."
+    )]
+    pub presets: Vec<Preset>,
+
+    #[arg(
+        long = "cfg",
+        value_name = "FLAG",
+        help = "Include #[if FLAG] blocks of the source. Can be repeated."
+    )]
+    pub flags: Vec<String>,
+
     pub files: Vec<PathBuf>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preset {
+    pub elf: String,
+    pub port: Port,
+    pub value: Int,
+}
+
+/// Parse `elf.port=value`, the port is written the same way as in santa code
+fn parse_preset(s: &str) -> Result<Preset, String> {
+    let (target, value) = s.split_once('=').ok_or("expected ELF.PORT=VALUE")?;
+    let (elf, port) = target.split_once('.').ok_or("expected ELF.PORT")?;
+
+    let mut port_chars = port.chars();
+    let port = match (port_chars.next(), port_chars.next()) {
+        (Some(d @ '0'..='9'), None) => d as Port - '0' as Port,
+        (Some(c), None) => c as Port,
+        _ => return Err(format!("invalid port \"{port}\"")),
+    };
+    let value = value
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid value \"{value}\": {e}"))?;
+
+    Ok(Preset {
+        elf: elf.trim().to_string(),
+        port,
+        value,
+    })
+}
+
 impl Args {
     pub fn validate(&self) -> Result<(), String> {
         match () {
             _ if self.files.is_empty() => Err("No files.".into()),
+            _ if !CELL_BITS.contains(&self.cell_bits) => {
+                Err("--cell-bits must be 8, 16, 32 or 64.".into())
+            }
             _ if self.interpret == false => {
                 Err("For now, only interpreter mode is supported. (see --help)".into())
             }
@@ -41,4 +120,50 @@ mod test {
         args1.validate().unwrap_err();
         args2.validate().unwrap();
     }
+
+    #[test]
+    fn parse_set() {
+        let args = Args::parse_from([
+            "santac", "-i", "a.sasm", "--set", "A.1=42", "--set", "B.x=-7",
+        ]);
+        assert_eq!(
+            args.presets,
+            [
+                Preset {
+                    elf: "A".into(),
+                    port: 1,
+                    value: 42
+                },
+                Preset {
+                    elf: "B".into(),
+                    port: 'x' as Port,
+                    value: -7
+                },
+            ]
+        );
+
+        for bad in ["A.1", "A=1", "A.12=1", "A.1=x"] {
+            assert!(
+                Args::try_parse_from(["santac", "--set", bad]).is_err(),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn cell_bits() {
+        let args = |bits| Args::parse_from(["santac", "-i", "a.sasm", "--cell-bits", bits]);
+        assert_eq!(args("8").cell_bits, 8);
+        args("16").validate().unwrap();
+        args("12").validate().unwrap_err();
+        assert_eq!(Args::parse_from(["santac", "a.sasm"]).cell_bits, 64);
+    }
+
+    #[test]
+    fn seed() {
+        let args = Args::parse_from(["santac", "-i", "a.sasm", "--seed", "42"]);
+        assert_eq!(args.seed, Some(42));
+        assert_eq!(Args::parse_from(["santac", "a.sasm"]).seed, None);
+        assert!(Args::try_parse_from(["santac", "--seed", "-1"]).is_err());
+    }
 }
@@ -0,0 +1,235 @@
+//! Step debugger for `--debug`, reads one command per line.
+//!
+//! - `step [n]`, or an empty line, makes `n` steps (default 1)
+//! - `continue` steps until the program ends or Ctrl-C stops it
+//! - `stack <elf>` prints the elf's stack
+//! - `watch <elf> top` or `watch <elf> sleeve <n>` prints the value whenever it changes
+//! - `quit`
+
+use std::io::{self, BufRead, Write};
+
+use santa_lang::{
+    ir::Int,
+    runtime::{Elf, Runtime, StepInfo, StepOk, TraceCode},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Top,
+    Sleeve(usize),
+}
+
+#[derive(Debug)]
+struct Watch {
+    elf: String,
+    target: Target,
+    /// Value after the last step, `None` when the elf is gone or there is nothing to read
+    last: Option<Int>,
+}
+
+impl Watch {
+    fn read(&self, rt: &Runtime) -> Option<Int> {
+        let elf = find_elf(rt, &self.elf)?;
+        match self.target {
+            Target::Top => elf.stack().last().copied(),
+            Target::Sleeve(slot) => elf.sleeve().get(slot).copied(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.target {
+            Target::Top => format!("{} top", self.elf),
+            Target::Sleeve(slot) => format!("{} sleeve {slot}", self.elf),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    watches: Vec<Watch>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run commands from `input` until it ends or says `quit`
+    pub fn run(
+        &mut self,
+        rt: &mut Runtime,
+        input: impl BufRead,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            let words: Vec<_> = line.split_whitespace().collect();
+            match words[..] {
+                [] | ["step"] => self.step(rt, 1, out)?,
+                ["step", n] => match n.parse() {
+                    Ok(n) => self.step(rt, n, out)?,
+                    Err(_) => writeln!(out, "invalid step count \"{n}\"")?,
+                },
+                ["continue"] => self.step(rt, u64::MAX, out)?,
+                ["stack", elf] => match find_elf(rt, elf) {
+                    Some(elf) => writeln!(out, "{:?}", elf.stack())?,
+                    None => writeln!(out, "no elf named {elf}")?,
+                },
+                ["watch", elf, "top"] => self.watch(rt, elf, Target::Top, out)?,
+                ["watch", elf, "sleeve", slot] => match slot.parse() {
+                    Ok(slot) if slot < 10 => self.watch(rt, elf, Target::Sleeve(slot), out)?,
+                    _ => writeln!(out, "invalid sleeve slot \"{slot}\"")?,
+                },
+                ["quit"] => break,
+                _ => writeln!(out, "unknown command \"{line}\"")?,
+            }
+        }
+        Ok(())
+    }
+
+    fn watch(
+        &mut self,
+        rt: &Runtime,
+        elf: &str,
+        target: Target,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut watch = Watch {
+            elf: elf.to_string(),
+            target,
+            last: None,
+        };
+        watch.last = watch.read(rt);
+        writeln!(out, "watch {}: {}", watch.describe(), show(watch.last))?;
+        self.watches.push(watch);
+        Ok(())
+    }
+
+    /// Make up to `n` steps, stops early when the program ends or is interrupted
+    fn step(&mut self, rt: &mut Runtime, n: u64, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..n {
+            match rt.step_once() {
+                Ok(StepOk::Stepped(info)) => {
+                    print_step(rt, &info, out)?;
+                    self.check_watches(rt, out)?;
+                }
+                Ok(StepOk::Interrupted) => return writeln!(out, "interrupted"),
+                Ok(StepOk::OutOfFuel) => return writeln!(out, "out of fuel"),
+                Ok(StepOk::Done) => return writeln!(out, "program ended"),
+                Err(e) => return writeln!(out, "{e}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_watches(&mut self, rt: &Runtime, out: &mut impl Write) -> io::Result<()> {
+        for watch in &mut self.watches {
+            let value = watch.read(rt);
+            if value != watch.last {
+                let (old, new) = (show(watch.last), show(value));
+                writeln!(out, "watch {}: {old} -> {new}", watch.describe())?;
+                watch.last = value;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn find_elf<'r>(rt: &'r Runtime, name: &str) -> Option<&'r Elf> {
+    rt.elves.values().find(|elf| elf.name() == name)
+}
+
+fn show(value: Option<Int>) -> String {
+    value.map_or("-".to_string(), |v| v.to_string())
+}
+
+fn print_step(rt: &Runtime, info: &StepInfo, out: &mut impl Write) -> io::Result<()> {
+    let who = match info.elf.and_then(|id| rt.elf(id)) {
+        Some(elf) => format!("elf {}", elf.name()),
+        None if info.elf.is_some() => "elf (asleep)".to_string(),
+        None => "santa".to_string(),
+    };
+    match &info.code {
+        Some(TraceCode::Elf(code)) => writeln!(out, "{who} > {:3} | {code:?}", info.ip),
+        Some(TraceCode::Santa(code)) => writeln!(out, "{who} > {:3} | {code:?}", info.ip),
+        None => writeln!(out, "{who} > {:3}", info.ip),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    use santa_lang::translate::{Options, TranslationInput, translate_with};
+
+    #[test]
+    fn watch_top() {
+        let src = "
+            workshop count:
+                floorplan:
+                    e> 01 01 +_ Hm
+                ;
+            ;
+            Santa will: setup count for elf Counter () ;
+        ";
+        let input = TranslationInput::Buffer {
+            name: None,
+            text: src.into(),
+        };
+        let unit = translate_with(vec![input], &Options::default()).unwrap();
+        let mut rt = Runtime::new(&unit);
+        let script = "step 2\nwatch Counter top\ncontinue\n";
+
+        let mut out = Vec::new();
+        Debugger::new()
+            .run(&mut rt, script.as_bytes(), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let watched: Vec<_> = out.lines().filter(|l| l.starts_with("watch")).collect();
+        assert_eq!(
+            watched,
+            [
+                "watch Counter top: -",
+                "watch Counter top: - -> 1",
+                "watch Counter top: 1 -> 2",
+                "watch Counter top: 2 -> -",
+            ],
+            "{out}"
+        );
+        assert!(out.ends_with("program ended\n"), "{out}");
+    }
+
+    #[test]
+    fn interrupted() {
+        let src = "
+            workshop busy:
+                floorplan:
+                    m> e> mv
+                    m^    m<
+                ;
+            ;
+            Santa will: setup busy for elf Busy () ;
+        ";
+        let input = TranslationInput::Buffer {
+            name: None,
+            text: src.into(),
+        };
+        let unit = translate_with(vec![input], &Options::default()).unwrap();
+        let mut rt = Runtime::new(&unit);
+        rt.stop_token().store(true, Ordering::Relaxed);
+
+        let mut out = Vec::new();
+        Debugger::new()
+            .run(&mut rt, "continue\nstep\n".as_bytes(), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("interrupted"), "{out}");
+        let next = lines.next().unwrap_or_default();
+        assert!(next.starts_with("santa"), "{out}");
+        assert!(!out.contains("program ended"), "{out}");
+    }
+}
@@ -1,10 +1,22 @@
-use std::{hash::Hash, mem, sync::Arc};
+use std::{
+    hash::Hash,
+    mem,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use clap::Parser;
-use santa_lang::{logger, runtime::{RunCommand, Runtime}, translate::{TranslationInput, translate}};
+use santa_lang::{
+    logger,
+    runtime::{RunCommand, RunOk, Runtime, SantaCode},
+    translate::{Options, TranslationInput, translate_with},
+};
 
 
 mod cli;
+mod debug;
 
 
 
@@ -23,14 +35,100 @@ fn main() {
         .map(|f| TranslationInput::File(f))
         .collect::<Vec<_>>();
 
-    let unit_res = translate(inputs);
+    let options = Options {
+        flags: mem::take(&mut args.flags).into_iter().collect(),
+    };
+    let unit_res = translate_with(inputs, &options);
 
-    let unit = logger::unwrap_many(unit_res);
+    let mut unit = logger::unwrap_many(unit_res);
+    unit.cell_bits = args.cell_bits;
     log::debug!("Parsing ok");
 
-    let mut rt = Runtime::new(&unit);
-    match rt.run(RunCommand::RunToEnd) {
+    let cmd = match args.max_steps {
+        Some(limit) => RunCommand::RunWithLimit(limit),
+        None => RunCommand::RunToEnd,
+    };
+
+    let mut rt = match args.seed {
+        Some(seed) => Runtime::new_seeded(&unit, seed),
+        None => Runtime::new(&unit),
+    };
+    log::debug!("Random seed {}, pass it to --seed to repeat it", rt.seed());
+    rt.pipe_capacity = args.pipe_capacity;
+    for preset in args.presets {
+        let known = unit.santa.iter().any(|code| match code {
+            SantaCode::SetupElf {
+                name: Some(name), ..
+            } => *name == preset.elf,
+            _ => false,
+        });
+        if !known {
+            log::error!("--set: there is no elf named {:?}", preset.elf);
+            std::process::exit(1);
+        }
+        rt.preset_input(preset.elf, preset.port, preset.value);
+    }
+
+    if let Err(e) = ctrlc::set_handler(interrupt_handler(rt.stop_token())) {
+        log::warn!("Ctrl-C will not stop the program cleanly: {e}");
+    }
+
+    if args.debug {
+        let stdin = std::io::stdin().lock();
+        if let Err(e) = debug::Debugger::new().run(&mut rt, stdin, &mut std::io::stdout()) {
+            log::error!("{e}");
+        }
+        return;
+    }
+
+    match rt.run(cmd) {
+        Ok(RunOk::LimitReached { steps }) => log::warn!("Program stopped after {steps} steps"),
+        Ok(RunOk::Interrupted { steps }) => log::warn!(
+            "Program interrupted after {steps} steps, {} elves still awake",
+            rt.elves.len()
+        ),
         Ok(_) => {},
         Err(e) => log::error!("{e}"),
     }
 }
+
+/// Ctrl-C stops the runtime before its next step, so that the outputs get flushed.
+/// Pressing it again while the runtime does not stop ends the process right away.
+fn interrupt_handler(stop: Arc<AtomicBool>) -> impl FnMut() + Send + 'static {
+    move || {
+        if stop.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interrupt_stops_runtime() {
+        let src = "
+            workshop busy:
+                floorplan:
+                    m> e> mv
+                    m^    m<
+                ;
+            ;
+            Santa will: setup busy for elf Busy () ;
+        ";
+        let input = TranslationInput::Buffer {
+            name: None,
+            text: src.into(),
+        };
+        let unit = translate_with(vec![input], &Options::default()).unwrap();
+        let mut rt = Runtime::new(&unit);
+
+        let mut handler = interrupt_handler(rt.stop_token());
+        handler();
+        assert!(matches!(
+            rt.run(RunCommand::RunToEnd),
+            Ok(RunOk::Interrupted { .. })
+        ));
+    }
+}
@@ -1,12 +1,24 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
 
 use crate::parse::Tile;
+pub use builder::*;
+
+mod builder;
+
+/// Widths `Unit::cell_bits` may have
+pub const CELL_BITS: [u32; 4] = [8, 16, 32, 64];
 
 #[derive(Debug)]
 /// Represents a executable code unit
 pub struct Unit {
     pub rooms: Vec<Room>,
     pub santa: Vec<SantaCode>,
+    /// Width of a sheet, one of 8, 16, 32 or 64 bits, the runtime rounds other widths up.
+    /// Pushed and computed values wrap around.
+    pub cell_bits: u32,
 }
 
 pub type Int = i64;
@@ -16,6 +28,9 @@ pub type RoomId = usize;
 pub type SantaLine = usize;
 pub type ElfLine = usize;
 
+/// Pushed by `Instr::Peek` when no input is waiting
+pub const NO_INPUT: Int = -1;
+
 #[derive(Debug, Clone)]
 /// Represents a SSA-like instruction
 pub enum SantaCode {
@@ -23,6 +38,8 @@ pub enum SantaCode {
     SetupElf {
         name: Option<String>,
         room: RoomId,
+        /// Instruction the elf begins with, see `Room::entries`
+        entry: ElfLine,
         init_stack: Vec<SantaLine>,
     },
     Connect {
@@ -37,6 +54,20 @@ pub enum SantaCode {
         src: (SantaLine, Port),
         file: Arc<str>,
     },
+    ReadEnv {
+        var: Arc<str>,
+        dst: (SantaLine, Port),
+    },
+    /// Write an elf's output to a named queue
+    ToQueue {
+        src: (SantaLine, Port),
+        queue: Arc<str>,
+    },
+    /// Read an elf's input from a named queue, readers of one queue take turns
+    FromQueue {
+        queue: Arc<str>,
+        dst: (SantaLine, Port),
+    },
     Monitor {
         port: (SantaLine, Port),
         block_len: usize,
@@ -46,7 +77,52 @@ pub enum SantaCode {
     /// send (elf, port, expr)
     Send(SantaLine, Port, SantaLine),
     Deliver(SantaLine),
+    DeliverStr(Arc<str>),
+    /// Do nothing for this many steps, `Wait(0)` does not even give up the turn
+    Wait(usize),
+    /// Skip the next `len` lines if `a cmp b` holds
+    SkipIf {
+        a: SantaLine,
+        cmp: Cmp,
+        b: SantaLine,
+        len: usize,
+    },
+    /// Run the next `block_len` lines as many times as `count` says, the last one is `EndRepeat`
+    Repeat {
+        count: SantaLine,
+        block_len: usize,
+    },
+    /// Jump back to the start of the repeat block on this line, unless it ran out
+    EndRepeat(SantaLine),
+}
+
+/// Resources a unit asks for, see [`Unit::resource_estimate`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceEstimate {
+    pub elves: usize,
+    pub connections: usize,
+    pub files: usize,
+}
+
+impl Unit {
+    /// Count the elves, pipes and files the santa code sets up, without running it.
+    /// Code inside monitor and repeat blocks is counted once, even though it may run many times.
+    pub fn resource_estimate(&self) -> ResourceEstimate {
+        let mut estimate = ResourceEstimate::default();
+        for code in &self.santa {
+            match code {
+                SantaCode::SetupElf { .. } => estimate.elves += 1,
+                SantaCode::Connect { .. }
+                | SantaCode::ToQueue { .. }
+                | SantaCode::FromQueue { .. } => estimate.connections += 1,
+                SantaCode::OpenRead { .. } | SantaCode::OpenWrite { .. } => estimate.files += 1,
+                _ => {}
+            }
+        }
+        estimate
+    }
 }
+
 impl SantaCode {
     pub(crate) fn unwrap_monitor(&self) -> ((SantaLine, Port), usize) {
         match self {
@@ -70,6 +146,8 @@ pub struct Room {
     pub size: (usize, usize),
     pub tiles: Vec<Tile<Arc<str>>>,
     pub elf_program: Vec<Instr>,
+    /// Where elves set up with `setup Shop.name` begin, by the name of their start tile
+    pub entries: HashMap<String, ElfLine>,
 }
 impl Room {
     pub fn get_tile(&self, x:usize,y:usize) -> &Tile<Arc<str>> {
@@ -85,21 +163,41 @@ pub enum Instr {
     Nop,
     Push(Int),
     Dup(usize),        // push n-th from top to the top
+    DupBlock(usize),   // push a copy of the top n, in the same order
     Erase(usize),      // remove n-th from top
+    Drop,              // remove top
     Tuck(usize),       // insert top before n-th from top
     Swap(usize),       // swap top with n-th from top
+    Rot(usize),        // move n-th from top to the top
+    RotDyn,            // pop k, rotate the rest of the stack towards the bottom by k
+    Pick,              // pop n, push n-th from top
     JmpPtr(ElfLine),   // jump to usize
     IfPosPtr(ElfLine), // if top>0, jump to usize
     IfNzPtr(ElfLine),  // if top!=0, jump to usize
     IfEmptyPtr(ElfLine), // if stack is empty, jump
     Arith(Op),
     ArithC(Op, Int),
+    Cmp(Cmp), // pop b, pop a, push 1 if `a cmp b` holds, 0 otherwise
+    Neg,      // negate top
+    DivMod,   // pop b, pop a, push a/b and then a%b
+    Rand,     // pop n, push a random number from 0 to n-1, see `Runtime::new_seeded`
     StackLen,
-    Read(u8),  // read sleeve slot, push on top
-    Write(u8), // write to sleeve slot, consuming top
+    Clear,      // drop the whole stack, nothing happens when it is already empty
+    Read(u8),   // read sleeve slot, push on top
+    Write(u8),  // write to sleeve slot, consuming top
+    DumpSleeve, // push every sleeve slot, slot 0 first
+    LoadSleeve, // pop 10 sheets into the sleeve, the top goes to slot 9
     In(Port),
+    Peek(Port), // push the next input without taking it, `NO_INPUT` if there is none
+    Poll(Port), // push 1 if input is waiting, 0 if not yet, -1 if the pipe is closed
     Out(Port),
+    Feed(Port),  // pop the top into the elf's own input port, `In` reads it from there
+    InLen(Port), // push how many inputs are waiting, without waiting for more
+    Tick,        // push the runtime's counter and count up, no two ticks push the same value
     Hammock,
+    Break,               // stop the runtime, see `RunCommand::Continue`
+    Probe(&'static str), // log the stack under this name, see `intern`
+    Extension(u16),      // reserved for experiments, see `Runtime::set_extension_handler`
 
     // human-friendly branches, only used in tests
     Label(&'static str),
@@ -115,6 +213,124 @@ pub enum Op {
     Mul,
     Div,
     Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl Op {
+    /// How the operation is written in error messages
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Mod => "%",
+            Op::And => "&",
+            Op::Or => "|",
+            Op::Xor => "^",
+            Op::Shl => "<<",
+            Op::Shr => ">>",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl Cmp {
+    /// Comparison that holds exactly when this one does not
+    pub fn negate(self) -> Cmp {
+        match self {
+            Cmp::Eq => Cmp::Ne,
+            Cmp::Ne => Cmp::Eq,
+            Cmp::Lt => Cmp::Ge,
+            Cmp::Gt => Cmp::Le,
+            Cmp::Le => Cmp::Gt,
+            Cmp::Ge => Cmp::Lt,
+        }
+    }
+}
+
+/// Instruction groups, used to filter the trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Stack = 1,
+    Arith = 2,
+    IO = 4,
+    Control = 8,
+}
+
+impl Category {
+    /// Mask with every category set
+    pub const ALL: u8 = 0b1111;
+
+    pub fn mask(self) -> u8 {
+        self as u8
+    }
+}
+
+impl Instr {
+    pub fn category(&self) -> Category {
+        use Instr::*;
+        match self {
+            Push(_) | Dup(_) | DupBlock(_) | Erase(_) | Drop | Tuck(_) | Swap(_) | Rot(_)
+            | RotDyn | Pick | StackLen | Clear | Read(_) | Write(_) | DumpSleeve | LoadSleeve => {
+                Category::Stack
+            }
+            Arith(_) | ArithC(..) | Cmp(_) | Neg | DivMod | Rand => Category::Arith,
+            In(_) | Peek(_) | Poll(_) | InLen(_) | Out(_) | Feed(_) | Tick => Category::IO,
+            Nop | JmpPtr(_) | IfPosPtr(_) | IfNzPtr(_) | IfEmptyPtr(_) | Hammock | Break
+            | Extension(_) | Probe(_) | Label(_) | Jmp(_) | IfPos(_) | IfNz(_) => Category::Control,
+        }
+    }
+
+    /// Sheets taken from the top and sheets put back, `( a b -- c )` is `(2, 1)`.
+    /// `None` when it depends on the stack or on the extension handler.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        use Instr::*;
+        Some(match *self {
+            Nop | JmpPtr(_) | IfEmptyPtr(_) | Hammock | Break | Probe(_) => (0, 0),
+            Push(_) | StackLen | Read(_) | In(_) | Peek(_) | Poll(_) | InLen(_) | Tick => (0, 1),
+            Dup(i) => (i + 1, i + 2),
+            DupBlock(n) => (n, 2 * n),
+            Erase(i) => (i + 1, i),
+            Tuck(i) | Swap(i) | Rot(i) => (i + 1, i + 1),
+            Drop | Write(_) | Out(_) | Feed(_) | IfPosPtr(_) | IfNzPtr(_) => (1, 0),
+            ArithC(..) | Neg | Rand => (1, 1),
+            Arith(_) | Cmp(_) => (2, 1),
+            DivMod => (2, 2),
+            DumpSleeve => (0, 10),
+            LoadSleeve => (10, 0),
+            RotDyn | Pick | Clear | Extension(_) => return None,
+            Label(_) | Jmp(_) | IfPos(_) | IfNz(_) => return None,
+        })
+    }
+}
+
+/// Give out one `&'static str` per distinct name, so that `Instr` can stay `Copy`.
+/// The names are never freed.
+pub fn intern(name: &str) -> &'static str {
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut names = NAMES.lock().unwrap();
+    match names.get(name) {
+        Some(interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(name.into());
+            names.insert(interned);
+            interned
+        }
+    }
 }
 
 pub fn to_port(src: char) -> Port {
@@ -126,8 +342,6 @@ impl Room {
     pub fn new_testing(mut elf_program: Vec<Instr>) -> Self {
         use std::mem;
 
-        use crate::parse::TileKind;
-
         let mut labels: HashMap<&str, usize> = HashMap::new();
         for (i, instr) in elf_program.iter().enumerate() {
             if let Instr::Label(name) = instr {
@@ -149,11 +363,95 @@ impl Room {
                 x => x,
             }
         }
+        Self::from_program(elf_program)
+    }
+
+    /// Listing of `elf_program`, one instruction per line with its index and tile.
+    /// Jump targets get labels like `L3:`, named after the index they point to.
+    pub fn disassemble(&self) -> String {
+        use Instr::*;
+
+        let targets: BTreeSet<ElfLine> = (self.elf_program.iter())
+            .filter_map(|instr| match instr {
+                JmpPtr(t) | IfPosPtr(t) | IfNzPtr(t) | IfEmptyPtr(t) => Some(*t),
+                _ => None,
+            })
+            .collect();
+
+        let mut out = String::new();
+        for (ip, instr) in self.elf_program.iter().enumerate() {
+            if targets.contains(&ip) {
+                out += &format!("L{ip}:\n");
+            }
+            let text = match instr {
+                JmpPtr(t) => format!("Jmp(L{t})"),
+                IfPosPtr(t) => format!("IfPos(L{t})"),
+                IfNzPtr(t) => format!("IfNz(L{t})"),
+                IfEmptyPtr(t) => format!("IfEmpty(L{t})"),
+                _ => format!("{instr:?}"),
+            };
+            out += &match self.ip_to_tile.get(&ip) {
+                Some((x, y)) => format!("{ip:>4}  {text:<20}; ({x}, {y})\n"),
+                None => format!("{ip:>4}  {text}\n"),
+            };
+        }
+        out
+    }
+
+    /// Room of a workshop without a floorplan, the elf runs `elf_program` from the top.
+    pub fn from_program(elf_program: Vec<Instr>) -> Self {
         Self {
             ip_to_tile: Default::default(),
-            size: (1,1),
-            tiles: vec![Tile{ text: "  ".into(), kind: TileKind::Empty }],
+            size: (1, 1),
+            tiles: vec![Tile {
+                text: "  ".into(),
+                kind: crate::parse::TileKind::Empty,
+            }],
             elf_program,
+            entries: HashMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimate_resources() {
+        let elf = |room| SantaCode::SetupElf {
+            name: None,
+            room,
+            entry: 0,
+            init_stack: vec![],
+        };
+        let unit = Unit {
+            rooms: vec![],
+            cell_bits: 64,
+            santa: vec![
+                elf(0),
+                elf(0),
+                elf(1),
+                SantaCode::Connect {
+                    src: (0, 1),
+                    dst: (1, 1),
+                },
+                SantaCode::Connect {
+                    src: (1, 1),
+                    dst: (2, 1),
+                },
+                SantaCode::OpenWrite {
+                    src: (2, 1),
+                    file: "out.txt".into(),
+                },
+            ],
+        };
+
+        let expected = ResourceEstimate {
+            elves: 3,
+            connections: 2,
+            files: 1,
+        };
+        assert_eq!(unit.resource_estimate(), expected);
+    }
+}
@@ -0,0 +1,167 @@
+//! Building a [`Unit`] without the text frontend
+//!
+//! Santa code refers to earlier lines by their index, the builder keeps track of them.
+//!
+//! ```
+//! use santa_lang::ir::*;
+//!
+//! let mut b = UnitBuilder::new();
+//! let room = b.add_room(vec![Instr::Push(72), Instr::Out(1)]);
+//! let elf = b.setup_elf(room, &[]);
+//! b.monitor(elf, 1, |b| {
+//!     let x = b.receive(elf, 1);
+//!     b.deliver(x);
+//! });
+//! let unit = b.build();
+//! ```
+
+use super::*;
+
+/// Elf set up by [`UnitBuilder::setup_elf`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfRef(SantaLine);
+
+/// Value santa knows, a constant or something he received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueRef(SantaLine);
+
+#[derive(Debug)]
+pub struct UnitBuilder {
+    rooms: Vec<Room>,
+    santa: Vec<SantaCode>,
+    cell_bits: u32,
+}
+
+impl Default for UnitBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UnitBuilder {
+    pub fn new() -> Self {
+        Self {
+            rooms: Vec::new(),
+            santa: Vec::new(),
+            cell_bits: 64,
+        }
+    }
+
+    /// Width of a sheet, see [`Unit::cell_bits`]. Fails for widths not in [`CELL_BITS`].
+    pub fn cell_bits(&mut self, bits: u32) -> Result<(), String> {
+        if !CELL_BITS.contains(&bits) {
+            return Err(format!("cell bits must be 8, 16, 32 or 64, not {bits}"));
+        }
+        self.cell_bits = bits;
+        Ok(())
+    }
+
+    /// Add a room where the elf runs `program` from the top, jumps use `*Ptr` instructions.
+    pub fn add_room(&mut self, program: Vec<Instr>) -> RoomId {
+        self.rooms.push(Room::from_program(program));
+        self.rooms.len() - 1
+    }
+
+    pub fn constant(&mut self, value: Int) -> ValueRef {
+        ValueRef(self.push(SantaCode::Const(value)))
+    }
+
+    pub fn setup_elf(&mut self, room: RoomId, stack: &[Int]) -> ElfRef {
+        let init_stack = stack.iter().map(|v| self.constant(*v).0).collect();
+        ElfRef(self.push(SantaCode::SetupElf {
+            name: None,
+            room,
+            entry: 0,
+            init_stack,
+        }))
+    }
+
+    pub fn connect(&mut self, src: ElfRef, src_port: Port, dst: ElfRef, dst_port: Port) {
+        self.push(SantaCode::Connect {
+            src: (src.0, src_port),
+            dst: (dst.0, dst_port),
+        });
+    }
+
+    /// Watch `port` of `elf`, santa runs the code `handler` adds when a sheet arrives.
+    pub fn monitor(&mut self, elf: ElfRef, port: Port, handler: impl FnOnce(&mut Self)) {
+        let start = self.push(SantaCode::Monitor {
+            port: (elf.0, port),
+            block_len: 0,
+        });
+        handler(self);
+        self.santa[start] = SantaCode::Monitor {
+            port: (elf.0, port),
+            block_len: self.santa.len() - start,
+        };
+    }
+
+    pub fn receive(&mut self, elf: ElfRef, port: Port) -> ValueRef {
+        ValueRef(self.push(SantaCode::Receive(elf.0, port)))
+    }
+
+    pub fn send(&mut self, elf: ElfRef, port: Port, value: ValueRef) {
+        self.push(SantaCode::Send(elf.0, port, value.0));
+    }
+
+    pub fn deliver(&mut self, value: ValueRef) {
+        self.push(SantaCode::Deliver(value.0));
+    }
+
+    pub fn build(self) -> Unit {
+        Unit {
+            rooms: self.rooms,
+            santa: self.santa,
+            cell_bits: self.cell_bits,
+        }
+    }
+
+    fn push(&mut self, code: SantaCode) -> SantaLine {
+        self.santa.push(code);
+        self.santa.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::assert_terminates;
+
+    #[test]
+    fn build_pipeline() {
+        use Instr::*;
+
+        let mut b = UnitBuilder::new();
+        let emit = b.add_room(vec![Out(1), Out(1)]);
+        let inc = b.add_room(vec![In(1), ArithC(Op::Add, 1), Out(1), JmpPtr(0)]);
+
+        let producer = b.setup_elf(emit, &[65, 66]);
+        let consumer = b.setup_elf(inc, &[]);
+        b.connect(producer, 1, consumer, 1);
+        b.monitor(consumer, 1, |b| {
+            let x = b.receive(consumer, 1);
+            b.deliver(x);
+        });
+
+        assert_eq!(assert_terminates(&b.build(), 100), "CB");
+    }
+
+    #[test]
+    fn cell_bits() {
+        let mut b = UnitBuilder::new();
+        b.cell_bits(8).unwrap();
+        let unit = b.build();
+        assert_eq!(unit.cell_bits, 8);
+        assert!(unit.rooms.is_empty() && unit.santa.is_empty());
+    }
+
+    #[test]
+    fn invalid_cell_bits() {
+        for bits in [0, 7, 65, 128] {
+            let mut b = UnitBuilder::new();
+            let error = b.cell_bits(bits).unwrap_err();
+            assert!(error.ends_with(&format!("not {bits}")), "{error}");
+            assert_eq!(b.build().cell_bits, 64);
+        }
+    }
+}
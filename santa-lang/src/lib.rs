@@ -143,11 +143,13 @@ mod test {
             SantaCode::SetupElf {
                 name: None,
                 room: 0,
+                entry: 0,
                 init_stack: vec![100],
             },
             SantaCode::SetupElf {
                 name: None,
                 room: 1,
+                entry: 0,
                 init_stack: vec![100],
             },
             SantaCode::Monitor {
@@ -161,6 +163,7 @@ mod test {
         let unit = Unit {
             rooms: vec![fizzbuzz, print],
             santa,
+            cell_bits: 64,
         };
 
         let mut rt = Runtime::new(&unit);
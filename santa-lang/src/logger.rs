@@ -51,6 +51,11 @@ impl log::Log for SimpleLogger {
             }
             .unwrap();
 
+            #[cfg(test)]
+            if capture::push(record) {
+                return;
+            }
+
             write!(&mut out, ": {}", record.args()).unwrap();
             println!("{out}");
         }
@@ -70,3 +75,35 @@ pub fn init(level: log::LevelFilter) {
             .expect("Failed to set logger");
     });
 }
+
+/// Run `f` and collect the messages it logs on this thread, instead of printing them.
+#[cfg(test)]
+pub(crate) fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    init(log::LevelFilter::Trace);
+    log::set_max_level(log::LevelFilter::Trace);
+
+    capture::LOGS.with_borrow_mut(|logs| *logs = Some(Vec::new()));
+    let result = f();
+    let logs = capture::LOGS.with_borrow_mut(Option::take);
+    (result, logs.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod capture {
+    use std::cell::RefCell;
+
+    thread_local! {
+        pub static LOGS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    /// Returns false when this thread is not capturing
+    pub fn push(record: &log::Record) -> bool {
+        LOGS.with_borrow_mut(|logs| match logs {
+            Some(logs) => {
+                logs.push(record.args().to_string());
+                true
+            }
+            None => false,
+        })
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::Debug;
 
 use peg::str::LineCol;
 
-use crate::ir::{Instr, Int};
+use crate::ir::{Cmp, Instr, Int};
 
 use super::*;
 
@@ -39,8 +39,13 @@ peg::parser! { grammar santasm() for str {
     pub rule shop() -> Shop<&'input str>
         = word("workshop") name:ident() ":" _ blocks:shop_block()* _ ";" _ { Shop { name, blocks } }
 
+    // any kind and number of blocks, translate checks that they make sense together
     rule shop_block() -> ShopBlock<&'input str>
         = word("floorplan") ":" p:plan()? _ ";" _ { p.unwrap_or(ShopBlock::empty_plan()) }
+        / word("program") ":" p:program()? _ ";" _ { ShopBlock::Program(p.unwrap_or_default()) }
+        / word("effect") ":" _ "(" _ takes:(ident() ** _) _ "--" _ puts:(ident() ** _) _ ")" _ {
+            ShopBlock::Effect { takes: takes.len(), puts: puts.len() }
+        }
 
     pub rule plan() -> ShopBlock<&'input str>
         = (__ NL())+ r1:plan_row(None) rs:plan_row(Some(&r1))* _ { ShopBlock::make_plan(r1, rs) }
@@ -50,29 +55,70 @@ peg::parser! { grammar santasm() for str {
             PlanRow { text: s.1, indent: s.0.0, tiles: s.0.1 }.matches(first)
         }
 
+    rule program() -> Vec<Instr>
+        = (__ NL())+ rows:program_row()+ { rows.concat() }
+
+    rule program_row() -> Vec<Instr>
+        = [' ' | '\t']* is:(program_instr() ++ " ") (__ NL())+ { is }
+
+    rule program_instr() -> Instr
+        = k:plan_tile_kind() {?
+            match k {
+                TileKind::Instr(i) => Ok(i),
+                _ => Err("instruction tile"),
+            }
+        }
+
     pub rule plan_tile() -> Tile<&'input str> =
         t:slice(<plan_tile_kind()>) { Tile { text: t.1, kind: t.0 } }
 
     rule plan_tile_kind() -> TileKind
         = ("  " / "..") { TileKind::Empty }
         / "m" d:dir() { TileKind::Move(d) }
-        / "e" d:dir() { TileKind::Elf(d) }
+        // named start, e.g. `e>A`, may be wider than two characters
+        / "e" d:dir() ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']* { TileKind::Elf(d) }
+        / "Cl" { TileKind::Instr(Instr::Clear) }
         / "C" c:tile_ch() { TileKind::Instr(Instr::Push(c as Int)) }
         / d1:digit() d0:digit() { TileKind::Instr(Instr::Push(d1 as Int * 10 + d0 as Int)) }
+        // wider than two characters, but still takes a single place in the row
+        / "#" n:$("-"? ['0'..='9']+) {? n.parse().map(|n| TileKind::Instr(Instr::Push(n))).or(Err("Int")) }
         / "D" d:digit() { TileKind::Instr(Instr::Dup(d)) }
+        / "B" d:digit() { TileKind::Instr(Instr::DupBlock(d)) }
         / "E" d:digit() { TileKind::Instr(Instr::Erase(d)) }
+        / "D_" { TileKind::Instr(Instr::Pick) }
+        / "Dr" { TileKind::Instr(Instr::Drop) }
+        / "Dm" { TileKind::Instr(Instr::DivMod) }
         / "S" d:digit() { TileKind::Instr(Instr::Swap(d)) }
+        / "T" d:digit() { TileKind::Instr(Instr::Tuck(d)) }
+        / "r_" { TileKind::Instr(Instr::RotDyn) }
+        / "r" d:digit() { TileKind::Instr(Instr::Rot(d)) }
         / "I" d:tile_param() { TileKind::Instr(Instr::In(d as u16)) }
         / "O" d:tile_param() { TileKind::Instr(Instr::Out(d as u16)) }
+        / "F" d:tile_param() { TileKind::Instr(Instr::Feed(d as u16)) }
+        / "P" d:tile_param() { TileKind::Instr(Instr::Peek(d as u16)) }
+        / "Q" d:tile_param() { TileKind::Instr(Instr::Poll(d as u16)) }
+        / "L" d:tile_param() { TileKind::Instr(Instr::InLen(d as u16)) }
         / "R" d:digit() { TileKind::Instr(Instr::Read(d as u8)) }
+        / "R*" { TileKind::Instr(Instr::DumpSleeve) }
+        / "W*" { TileKind::Instr(Instr::LoadSleeve) }
         / "W" d:digit() { TileKind::Instr(Instr::Write(d as u8)) }
         / "Hm" { TileKind::Instr(Instr::Hammock) }
+        / "Br" { TileKind::Instr(Instr::Break) }
+        / "[]" { TileKind::Wall }
+        / "@" c:tile_ch() { TileKind::Portal(c) }
+        / "$" ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+ { TileKind::Probe }
+        / "X" d:tile_param() { TileKind::Instr(Instr::Extension(d as u16)) }
         / "?=" { TileKind::IsZero }
         / "?>" { TileKind::IsPos }
         / "?<" { TileKind::IsNeg }
         / "?s" { TileKind::IsEmpty }
+        / "??" { TileKind::Instr(Instr::Rand) }
         / "!s" { TileKind::Instr(Instr::StackLen) }
-        / "*-" { TileKind::Instr(Instr::ArithC(runtime::Op::Mul, -1)) }
+        / "!t" { TileKind::Instr(Instr::Tick) }
+        / ("~-" / "*-") { TileKind::Instr(Instr::Neg) }
+        / cmp:cmp_op() { TileKind::Instr(Instr::Cmp(cmp)) }
+        / "<<" { TileKind::Instr(Instr::Arith(runtime::Op::Shl)) }
+        / ">>" { TileKind::Instr(Instr::Arith(runtime::Op::Shr)) }
         / op:arith_op() "_" { TileKind::Instr(Instr::Arith(op)) }
         / op:arith_op() d:digit() { TileKind::Instr(Instr::ArithC(op, d as Int)) }
         // s:$(tile_ch()*<2>) { TileKind::Unknown(s) }
@@ -89,6 +135,19 @@ peg::parser! { grammar santasm() for str {
         / "*" { runtime::Op::Mul }
         / "/" { runtime::Op::Div }
         / "%" { runtime::Op::Mod }
+        / "&" { runtime::Op::And }
+        / "|" { runtime::Op::Or }
+        / "^" { runtime::Op::Xor }
+        / "<" { runtime::Op::Shl }
+        / ">" { runtime::Op::Shr }
+
+    rule cmp_op() -> Cmp
+        = "=." { Cmp::Eq }
+        / "!=" { Cmp::Ne }
+        / "<=" { Cmp::Le }
+        / ">=" { Cmp::Ge }
+        / "<." { Cmp::Lt }
+        / ">." { Cmp::Gt }
 
     rule tile_param() -> Int
         = d:digit() { d as Int }
@@ -103,9 +162,9 @@ peg::parser! { grammar santasm() for str {
         }
 
     rule todo_item() -> ToDo<&'input str>
-        = word("setup") shop:ident() word("for") h:helper_type() name:ident()? "(" stack:val_expr()* ")"
+        = word("setup") shop:ident() entry:("." e:ident() {e})? word("for") h:helper_type() name:ident()? "(" stack:val_expr()* ")"
             { match h {
-                HelperType::Elf => ToDo::SetupElf { name, stack, shop },
+                HelperType::Elf => ToDo::SetupElf { name, stack, shop, entry },
                 HelperType::Raindeer => todo!("raindeer"),
             } }
         / word("setup") src:connection("STDIN") "->" dst:connection("STDOUT")
@@ -116,7 +175,12 @@ peg::parser! { grammar santasm() for str {
             { ToDo::Receive { vars: vs, src } }
         / word("send") vs:list(<val_expr()>) dst:(word("to") p:helper_port() {p})?
             { ToDo::Send { values: vs, dst } }
-        / word("deliver") e:val_expr() { ToDo::Deliver { e } }
+        / word("deliver") text:strlit() { ToDo::DeliverStr { text } }
+        / word("deliver") e:val_expr() cond:(word("if") c:condition() {c})?
+            { ToDo::Deliver { e, cond } }
+        / word("wait") _ n:$(['0'..='9']+) _ {? n.parse().map(|steps| ToDo::Wait { steps }).or(Err("usize")) }
+        / word("repeat") count:val_expr() ":" _ ts:todo_item()* _ ";" _
+            { ToDo::Repeat { count, todos: ts } }
 
 
     rule helper_type() -> HelperType
@@ -125,12 +189,25 @@ peg::parser! { grammar santasm() for str {
 
     rule connection(std: &'static str) -> Connection<&'input str>
         = word("FILE") "(" name:strlit() ")" _ { Connection::File(name) }
+        / word("ENV") "(" name:strlit() ")" _ { Connection::Env(name) }
+        / word("QUEUE") "(" name:strlit() ")" _ { Connection::Queue(name) }
         // word(std) { Connection::Std }
         / p:helper_port() { Connection::Port(p.0, p.1) }
 
     rule helper_port() -> (&'input str, char)
         = name:ident() "." _ port:tile_param() _ { (name, port as u8 as char) }
 
+    rule condition() -> Condition<&'input str>
+        = left:val_expr() cmp:santa_cmp() right:val_expr() { Condition { left, cmp, right } }
+
+    rule santa_cmp() -> Cmp
+        = _ c:("==" { Cmp::Eq }
+        / "!=" { Cmp::Ne }
+        / "<=" { Cmp::Le }
+        / ">=" { Cmp::Ge }
+        / "<" { Cmp::Lt }
+        / ">" { Cmp::Gt }) _ { c }
+
     rule val_expr() -> Expr<&'input str>
         = v:numInt() { Expr::Number(v) }
         / id:ident() { Expr::Var(id) }
@@ -177,7 +254,7 @@ peg::parser! { grammar santasm() for str {
 
     rule NL() = "\n" / "\r\n"
 
-    rule __ -> usize = s:$(quiet!{[' ' | '\r' | '\x0b' | '\x0c' | '\t']* ("#" [^'\n']*)? }) { s.len() }
+    rule __ -> usize = s:$(quiet!{[' ' | '\r' | '\x0b' | '\x0c' | '\t']* (("#" / "//") [^'\n']*)? }) { s.len() }
     rule _ -> usize = s:$( __ ** NL() ) { s.len() }
 }}
 
@@ -440,11 +517,13 @@ mod test {
             todos: vec![
                 ToDo::SetupElf {
                     shop: "toys",
+                    entry: None,
                     name: Some("Josh".into()),
                     stack: vec![Expr::Number(1), Expr::Number(2), Expr::Number(3)],
                 },
                 ToDo::SetupElf {
                     shop: "prod",
+                    entry: None,
                     name: Some("Bob".into()),
                     stack: vec![],
                 },
@@ -469,6 +548,7 @@ mod test {
                         },
                         ToDo::SetupElf {
                             shop: "sweets",
+                            entry: None,
                             name: Some("Alice".into()),
                             stack: vec![Expr::Number(4), Expr::Number(5)],
                         },
@@ -543,6 +623,72 @@ mod test {
         pretty_assertions::assert_eq!(expected, shop);
     }
 
+    #[test]
+    fn parse_slash_comment() {
+        let shop = santasm::shop(
+            "
+                workshop test: // hello
+                    floorplan:  // test
+                    e> .. mv // test
+                       .. 00 // test
+                    ;
+                ;
+            ",
+        );
+
+        let shop = match shop {
+            Err(e) => panic!("{e}"),
+            Ok(s) => s,
+        };
+
+        use TileKind::*;
+        let expected = Shop {
+            name: "test",
+            blocks: vec![ShopBlock::Plan {
+                width: 3,
+                height: 2,
+                map: vec![
+                    t("e>", Elf(Direction::Right)),
+                    t("..", Empty),
+                    t("mv", Move(Direction::Down)),
+                    t("  ", Empty),
+                    t("..", Empty),
+                    t("00", Instr(runtime::Instr::Push(0))),
+                ],
+            }],
+        };
+
+        pretty_assertions::assert_eq!(expected, shop);
+    }
+
+    #[test]
+    fn parse_slash_comment_santa() {
+        let mut tu = TranslationUnit::default();
+        let r = santasm::santa_block(
+            "
+                Santa will: // the plan
+                    // setup toys for elf Josh (1 2 3)
+                    setup FILE(\"http://santa//list\") -> Bob.1 // not in the string
+                ;
+            ",
+            &mut tu,
+        );
+
+        if let Err(e) = r {
+            panic!("{e}")
+        };
+
+        let expected = TranslationUnit {
+            workshops: Default::default(),
+            todos: vec![ToDo::Connect {
+                src: Connection::File("http://santa//list"),
+                dst: Connection::Port("Bob", 1 as char),
+            }],
+        };
+
+        pretty_assertions::assert_eq!(expected, tu);
+    }
+
     #[test]
     fn parse_tile() {
         let tile_r = santasm::plan_tile("e>");
@@ -559,4 +705,382 @@ mod test {
 
         pretty_assertions::assert_eq!(expected, tile);
     }
+
+    #[test]
+    fn parse_rotate_tile() {
+        let tile = santasm::plan_tile("r_").unwrap();
+        pretty_assertions::assert_eq!(t("r_", TileKind::Instr(Instr::RotDyn)), tile);
+    }
+
+    #[test]
+    fn parse_breakpoint_tile() {
+        let tile = santasm::plan_tile("Br").unwrap();
+        pretty_assertions::assert_eq!(t("Br", TileKind::Instr(Instr::Break)), tile);
+    }
+
+    #[test]
+    fn parse_compare_tiles() {
+        for (text, cmp) in [
+            ("=.", Cmp::Eq),
+            ("!=", Cmp::Ne),
+            ("<.", Cmp::Lt),
+            (">.", Cmp::Gt),
+            ("<=", Cmp::Le),
+            (">=", Cmp::Ge),
+        ] {
+            let tile = santasm::plan_tile(text).unwrap();
+            pretty_assertions::assert_eq!(t(text, TileKind::Instr(Instr::Cmp(cmp))), tile);
+        }
+    }
+
+    #[test]
+    fn plan_to_text_round_trip() {
+        let plan = parse_plan(
+            "
+                   mv    S1 -1 m<
+                         m>       Hm
+                e> m> D1 ?>    S1
+                         m> D0 ?>
+                         m^ -1 ..
+            ",
+        )
+        .unwrap();
+
+        let text = plan.to_text().unwrap();
+        pretty_assertions::assert_eq!(
+            text,
+            [
+                "   mv    S1 -1 m<",
+                "         m>       Hm",
+                "e> m> D1 ?>    S1",
+                "         m> D0 ?>",
+                "         m^ -1 ..",
+                "",
+            ]
+            .join("\n")
+        );
+
+        let input = format!("\n{text}");
+        let reparsed = parse_plan(&input).unwrap();
+        pretty_assertions::assert_eq!(reparsed, plan);
+        assert_eq!(reparsed.to_text().unwrap(), text);
+    }
+
+    #[test]
+    fn parse_bitwise_tiles() {
+        use runtime::Op::*;
+        for (text, instr) in [
+            ("&_", Instr::Arith(And)),
+            ("|_", Instr::Arith(Or)),
+            ("^_", Instr::Arith(Xor)),
+            ("<<", Instr::Arith(Shl)),
+            (">>", Instr::Arith(Shr)),
+            ("&3", Instr::ArithC(And, 3)),
+            ("<4", Instr::ArithC(Shl, 4)),
+            (">1", Instr::ArithC(Shr, 1)),
+        ] {
+            let tile = santasm::plan_tile(text).unwrap();
+            pretty_assertions::assert_eq!(t(text, TileKind::Instr(instr)), tile);
+        }
+    }
+
+    #[test]
+    fn parse_negate_tiles() {
+        for text in ["~-", "*-"] {
+            let tile = santasm::plan_tile(text).unwrap();
+            pretty_assertions::assert_eq!(t(text, TileKind::Instr(Instr::Neg)), tile);
+        }
+    }
+
+    #[test]
+    fn parse_drop_tile() {
+        let tile = santasm::plan_tile("Dr").unwrap();
+        pretty_assertions::assert_eq!(t("Dr", TileKind::Instr(Instr::Drop)), tile);
+    }
+
+    #[test]
+    fn parse_rot_tile() {
+        let tile = santasm::plan_tile("r2").unwrap();
+        pretty_assertions::assert_eq!(t("r2", TileKind::Instr(Instr::Rot(2))), tile);
+    }
+
+    #[test]
+    fn parse_env_connection() {
+        let mut tu = TranslationUnit::default();
+        santasm::santa_block("Santa will: setup ENV(\"SEED\") -> Bob.1 ;", &mut tu).unwrap();
+
+        let expected = vec![ToDo::Connect {
+            src: Connection::Env("SEED"),
+            dst: Connection::Port("Bob", 1 as char),
+        }];
+        pretty_assertions::assert_eq!(expected, tu.todos);
+    }
+
+    #[test]
+    fn parse_wide_constant_tiles() {
+        let tile = santasm::plan_tile("#255").unwrap();
+        pretty_assertions::assert_eq!(t("#255", TileKind::Instr(Instr::Push(255))), tile);
+        let tile = santasm::plan_tile("#-7").unwrap();
+        pretty_assertions::assert_eq!(t("#-7", TileKind::Instr(Instr::Push(-7))), tile);
+
+        // a comment still needs a space after `#`
+        let plan = parse_plan(
+            "
+                e> #255 Hm # push
+                .. .. ..
+            ",
+        )
+        .unwrap();
+        let ShopBlock::Plan { width, map, .. } = plan else {
+            panic!("not a plan");
+        };
+        assert_eq!(width, 3);
+        assert_eq!(map[1].kind, TileKind::Instr(Instr::Push(255)));
+        assert_eq!(map[2].kind, TileKind::Instr(Instr::Hammock));
+
+        // without the space the comment starts with a tile, and the rest of it is no tile
+        let parses = |comment| parse_plan(&format!("\n e> Hm {comment}\n")).is_ok();
+        for comment in ["# 3 loops", "# -1 is the end"] {
+            assert!(parses(comment), "{comment}");
+        }
+        for comment in ["#3 loops", "#-1 is the end"] {
+            assert!(!parses(comment), "{comment}");
+        }
+    }
+
+    #[test]
+    fn parse_tuck_tile() {
+        let tile = santasm::plan_tile("T2").unwrap();
+        pretty_assertions::assert_eq!(t("T2", TileKind::Instr(Instr::Tuck(2))), tile);
+    }
+
+    #[test]
+    fn parse_dup_block_tile() {
+        let tile = santasm::plan_tile("B2").unwrap();
+        pretty_assertions::assert_eq!(t("B2", TileKind::Instr(Instr::DupBlock(2))), tile);
+    }
+
+    #[test]
+    fn parse_probe_tile() {
+        let tile = santasm::plan_tile("$loop_2").unwrap();
+        pretty_assertions::assert_eq!(t("$loop_2", TileKind::Probe), tile);
+        assert!(santasm::plan_tile("$").is_err());
+    }
+
+    #[test]
+    fn parse_named_start_tile() {
+        let tile = santasm::plan_tile("e>A").unwrap();
+        pretty_assertions::assert_eq!(t("e>A", TileKind::Elf(Direction::Right)), tile);
+
+        let mut tu = TranslationUnit::default();
+        santasm::santa_block("Santa will: setup shop.A for elf Bob () ;", &mut tu).unwrap();
+        let expected = vec![ToDo::SetupElf {
+            shop: "shop",
+            entry: Some("A"),
+            name: Some("Bob"),
+            stack: vec![],
+        }];
+        pretty_assertions::assert_eq!(expected, tu.todos);
+    }
+
+    #[test]
+    fn parse_pick_tile() {
+        let tile = santasm::plan_tile("D_").unwrap();
+        pretty_assertions::assert_eq!(t("D_", TileKind::Instr(Instr::Pick)), tile);
+    }
+
+    #[test]
+    fn parse_sleeve_block_tiles() {
+        let tile = santasm::plan_tile("R*").unwrap();
+        pretty_assertions::assert_eq!(t("R*", TileKind::Instr(Instr::DumpSleeve)), tile);
+        let tile = santasm::plan_tile("W*").unwrap();
+        pretty_assertions::assert_eq!(t("W*", TileKind::Instr(Instr::LoadSleeve)), tile);
+    }
+
+    #[test]
+    fn parse_rand_tile() {
+        let tile = santasm::plan_tile("??").unwrap();
+        pretty_assertions::assert_eq!(t("??", TileKind::Instr(Instr::Rand)), tile);
+    }
+
+    #[test]
+    fn parse_tick_tile() {
+        let tile = santasm::plan_tile("!t").unwrap();
+        pretty_assertions::assert_eq!(t("!t", TileKind::Instr(Instr::Tick)), tile);
+    }
+
+    #[test]
+    fn parse_feed_tile() {
+        let tile = santasm::plan_tile("F1").unwrap();
+        pretty_assertions::assert_eq!(t("F1", TileKind::Instr(Instr::Feed(1))), tile);
+    }
+
+    #[test]
+    fn parse_divmod_tile() {
+        let tile = santasm::plan_tile("Dm").unwrap();
+        pretty_assertions::assert_eq!(t("Dm", TileKind::Instr(Instr::DivMod)), tile);
+    }
+
+    #[test]
+    fn parse_portal_tile() {
+        let tile = santasm::plan_tile("@a").unwrap();
+        pretty_assertions::assert_eq!(t("@a", TileKind::Portal('a')), tile);
+    }
+
+    #[test]
+    fn parse_wall_tile() {
+        let tile = santasm::plan_tile("[]").unwrap();
+        pretty_assertions::assert_eq!(t("[]", TileKind::Wall), tile);
+    }
+
+    #[test]
+    fn parse_peek_tile() {
+        let tile = santasm::plan_tile("P1").unwrap();
+        pretty_assertions::assert_eq!(t("P1", TileKind::Instr(Instr::Peek(1))), tile);
+    }
+
+    #[test]
+    fn parse_clear_tile() {
+        let tile = santasm::plan_tile("Cl").unwrap();
+        pretty_assertions::assert_eq!(t("Cl", TileKind::Instr(Instr::Clear)), tile);
+        let tile = santasm::plan_tile("Ck").unwrap();
+        pretty_assertions::assert_eq!(t("Ck", TileKind::Instr(Instr::Push('k' as Int))), tile);
+    }
+
+    #[test]
+    fn parse_in_len_tile() {
+        let tile = santasm::plan_tile("L1").unwrap();
+        pretty_assertions::assert_eq!(t("L1", TileKind::Instr(Instr::InLen(1))), tile);
+    }
+
+    #[test]
+    fn parse_poll_tile() {
+        let tile = santasm::plan_tile("Qa").unwrap();
+        pretty_assertions::assert_eq!(t("Qa", TileKind::Instr(Instr::Poll('a' as u16))), tile);
+    }
+
+    #[test]
+    fn parse_repeat() {
+        let mut tu = TranslationUnit::default();
+        santasm::santa_block(
+            "
+            Santa will:
+                repeat n:
+                    setup worker for elf ()
+                ;
+            ;
+            ",
+            &mut tu,
+        )
+        .unwrap();
+
+        let expected = vec![ToDo::Repeat {
+            count: Expr::Var("n"),
+            todos: vec![ToDo::SetupElf {
+                shop: "worker",
+                entry: None,
+                name: None,
+                stack: vec![],
+            }],
+        }];
+        pretty_assertions::assert_eq!(expected, tu.todos);
+    }
+
+    #[test]
+    fn parse_deliver_if() {
+        let mut tu = TranslationUnit::default();
+        santasm::santa_block("Santa will: deliver x if x >= 10 deliver 65 ;", &mut tu).unwrap();
+
+        let expected = vec![
+            ToDo::Deliver {
+                e: Expr::Var("x"),
+                cond: Some(Condition {
+                    left: Expr::Var("x"),
+                    cmp: Cmp::Ge,
+                    right: Expr::Number(10),
+                }),
+            },
+            ToDo::Deliver {
+                e: Expr::Number(65),
+                cond: None,
+            },
+        ];
+        pretty_assertions::assert_eq!(expected, tu.todos);
+    }
+
+    #[test]
+    fn parse_wait() {
+        let mut tu = TranslationUnit::default();
+        santasm::santa_block("Santa will: wait 10 deliver 65 ;", &mut tu).unwrap();
+
+        let expected = vec![
+            ToDo::Wait { steps: 10 },
+            ToDo::Deliver {
+                e: Expr::Number(65),
+                cond: None,
+            },
+        ];
+        pretty_assertions::assert_eq!(expected, tu.todos);
+    }
+
+    #[test]
+    fn parse_blocks_in_any_order() {
+        fn blocks(src: &str) -> Vec<ShopBlock<&str>> {
+            santasm::shop(src).unwrap().blocks
+        }
+        let plan = ShopBlock::empty_plan();
+        let program = ShopBlock::Program(vec![
+            Instr::Push(1),
+            Instr::Push(2),
+            Instr::Arith(runtime::Op::Add),
+            Instr::Out(1),
+        ]);
+
+        let program_first = blocks(
+            "workshop test:
+                program:
+                    01 02  // three
+                    +_ O1
+                ;
+                floorplan: ;
+            ;",
+        );
+        pretty_assertions::assert_eq!(program_first, vec![program, plan.clone()]);
+
+        let plan_first = blocks("workshop test: floorplan: ; program: ; program: ; ;");
+        let empty = ShopBlock::Program(vec![]);
+        pretty_assertions::assert_eq!(plan_first, vec![plan, empty.clone(), empty]);
+
+        assert!(santasm::shop("workshop test: program:\n 01 m>\n ; ;").is_err());
+    }
+
+    #[test]
+    fn parse_effect() {
+        let blocks = |src| santasm::shop(src).unwrap().blocks;
+        let effect = |takes, puts| vec![ShopBlock::Effect { takes, puts }];
+
+        assert_eq!(blocks("workshop w: effect: (a b -- c) ;"), effect(2, 1));
+        assert_eq!(blocks("workshop w: effect: ( -- ) ;"), effect(0, 0));
+        assert_eq!(blocks("workshop w: effect:(x--sum carry) ;"), effect(1, 2));
+        assert!(santasm::shop("workshop w: effect: (a b) ;").is_err());
+    }
+
+    #[test]
+    fn parse_queue_connection() {
+        let mut tu = TranslationUnit::default();
+        let src = "Santa will: setup QUEUE(\"work\") -> Bob.1 setup Amy.x -> QUEUE(\"work\") ;";
+        santasm::santa_block(src, &mut tu).unwrap();
+
+        let expected = vec![
+            ToDo::Connect {
+                src: Connection::Queue("work"),
+                dst: Connection::Port("Bob", 1 as char),
+            },
+            ToDo::Connect {
+                src: Connection::Port("Amy", 'x'),
+                dst: Connection::Queue("work"),
+            },
+        ];
+        pretty_assertions::assert_eq!(expected, tu.todos);
+    }
 }
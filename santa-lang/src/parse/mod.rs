@@ -2,7 +2,10 @@
 
 use std::{collections::HashMap, hash::Hash};
 
-use crate::{ir::Int, runtime};
+use crate::{
+    ir::{Cmp, Int},
+    runtime,
+};
 
 mod grammar;
 pub use grammar::*;
@@ -31,6 +34,37 @@ pub enum ShopBlock<S> {
         map: Vec<Tile<S>>,
     },
     Program(Vec<runtime::Instr>),
+    /// `effect: (a b -- c)`, how many sheets the elf takes from its stack and puts back
+    Effect {
+        takes: usize,
+        puts: usize,
+    },
+}
+
+impl<S: AsRef<str>> ShopBlock<S> {
+    /// Lay out a floorplan as text, one row per line and tiles separated by a space.
+    /// Blank tiles are written as spaces, unless they were spelled `..`.
+    /// Returns `None` for blocks other than floorplan.
+    pub fn to_text(&self) -> Option<String> {
+        let ShopBlock::Plan { width, map, .. } = self else {
+            return None;
+        };
+
+        let mut text = String::new();
+        for row in map.chunks((*width).max(1)) {
+            let tiles: Vec<&str> = row
+                .iter()
+                .map(|tile| match (&tile.kind, tile.text.as_ref()) {
+                    (TileKind::Empty, "..") => "..",
+                    (TileKind::Empty, _) => "  ",
+                    (_, text) => text,
+                })
+                .collect();
+            text += tiles.join(" ").trim_end();
+            text += "\n";
+        }
+        Some(text)
+    }
 }
 
 type Indent = (char, usize);
@@ -65,6 +99,12 @@ pub enum TileKind {
     /// Is the stack empty?
     IsEmpty,
     Instr(runtime::Instr),
+    /// Logs the stack, the name is the tile text after `$`
+    Probe,
+    /// `[]`, the path ends here and the elf falls asleep
+    Wall,
+    /// `@<c>`, the elf continues past the other portal with the same character
+    Portal(char),
     Unknown,
 }
 impl TileKind {
@@ -109,6 +149,8 @@ pub enum ToDo<S> {
     /// Connect output of one shop to input of another shop.
     SetupElf {
         shop: S,
+        /// Named start tile the elf begins on, e.g. `A` for `e>A`
+        entry: Option<S>,
         name: Option<S>,
         stack: Vec<Expr<S>>,
     },
@@ -132,13 +174,25 @@ pub enum ToDo<S> {
     },
     Deliver {
         e: Expr<S>,
+        /// Deliver only when this holds
+        cond: Option<Condition<S>>,
     },
+    /// Print a string literal, escapes are not resolved yet
+    DeliverStr { text: S },
+    /// Let time pass, santa does nothing for this many steps.
+    Wait { steps: usize },
+    /// Do the todos `count` times, the count is known only at runtime.
+    Repeat { count: Expr<S>, todos: Vec<ToDo<S>> },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Connection<S> {
     Port(S, char),
     File(S),
+    /// Environment variable, read once at startup
+    Env(S),
+    /// Named queue, any elf can write to it and read from it
+    Queue(S),
     Std,
 }
 
@@ -148,6 +202,14 @@ pub enum Expr<S> {
     Var(S),
 }
 
+/// Comparison of two values santa knows, e.g. `x > 0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition<S> {
+    pub left: Expr<S>,
+    pub cmp: Cmp,
+    pub right: Expr<S>,
+}
+
 impl<S: Clone + Eq + Hash> Default for TranslationUnit<S> {
     fn default() -> Self {
         Self {
@@ -186,6 +248,7 @@ impl<S> ShopBlock<S> {
                 map: map.into_iter().map(|t| t.convert(f)).collect(),
             },
             ShopBlock::Program(instrs) => ShopBlock::Program(instrs),
+            ShopBlock::Effect { takes, puts } => ShopBlock::Effect { takes, puts },
         }
     }
 }
@@ -201,9 +264,15 @@ impl<S> ToDo<S> {
     pub fn convert<R>(self, f: &impl Fn(S) -> R) -> ToDo<R> {
         use ToDo::*;
         match self {
-            SetupElf { name, stack, shop } => SetupElf {
+            SetupElf {
+                name,
+                stack,
+                shop,
+                entry,
+            } => SetupElf {
                 name: name.map(f),
                 shop: f(shop),
+                entry: entry.map(f),
                 stack: stack.into_iter().map(|i| i.convert(f)).collect(),
             },
             Connect { src, dst } => Connect {
@@ -222,7 +291,16 @@ impl<S> ToDo<S> {
                 dst: dst.map(|x| (f(x.0), x.1)),
                 values: values.into_iter().map(|x| x.convert(f)).collect(),
             },
-            Deliver { e } => Deliver { e: e.convert(f) },
+            Deliver { e, cond } => Deliver {
+                e: e.convert(f),
+                cond: cond.map(|c| c.convert(f)),
+            },
+            DeliverStr { text } => DeliverStr { text: f(text) },
+            Wait { steps } => Wait { steps },
+            Repeat { count, todos } => Repeat {
+                count: count.convert(f),
+                todos: todos.into_iter().map(|x| x.convert(f)).collect(),
+            },
         }
     }
 }
@@ -232,6 +310,8 @@ impl<S> Connection<S> {
         match self {
             Port(iden, c) => Port(f(iden), c),
             File(name) => File(f(name)),
+            Env(name) => Env(f(name)),
+            Queue(name) => Queue(f(name)),
             Std => Std,
         }
     }
@@ -244,6 +324,15 @@ impl<S> Expr<S> {
         }
     }
 }
+impl<S> Condition<S> {
+    pub fn convert<R>(self, f: &impl Fn(S) -> R) -> Condition<R> {
+        Condition {
+            left: self.left.convert(f),
+            cmp: self.cmp,
+            right: self.right.convert(f),
+        }
+    }
+}
 
 #[test]
 fn demonstrate_convert() {
@@ -263,6 +352,7 @@ fn demonstrate_convert() {
             )]),
             todos: vec![ToDo::SetupElf {
                 shop: names[0],
+                entry: None,
                 name: Some(names[1]),
                 stack: vec![],
             }],
@@ -1,13 +1,22 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    fmt, fs, io, usize,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt, fs, io,
+    path::Path,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+    usize,
 };
 
 use crate::DropGuard;
 pub use crate::ir::*;
 pub use pipe::*;
+pub use pool::RuntimePool;
 
 mod pipe;
+mod pool;
 
 #[derive(Debug)]
 pub struct Runtime<'u> {
@@ -18,21 +27,97 @@ pub struct Runtime<'u> {
     next_elf_id: ElfId,
     /// Stores active elves. They get deleted when they finish.
     pub elves: HashMap<ElfId, Elf>,
-    /// Queue for elf scheduling.
+    /// Queue for elf scheduling, the front turn runs next. See `run` for the order.
     schedule: VecDeque<Turn>,
     /// Each monitor is a pair of (pipe, santa_handler_ptr)
     monitors: HashMap<(ElfId, Port), (InputPipe<Int>, SantaLine)>,
+    /// How many times in a row each monitor was triggered by values its own handler sent
+    monitor_depth: HashMap<SantaLine, usize>,
+    /// Monitors whose running handler sent something
+    monitor_sent: HashSet<SantaLine>,
+    /// Pipes for santa's send command, closed once everyone is waiting on them
+    santa_outputs: HashMap<(ElfId, Port), OutputPipe<Int>>,
     /// Output of the santa's deliver command
     pub output: Out,
+    /// How elves share the scheduler
+    pub policy: SchedulePolicy,
+    /// Most steps an elf may take in a row before it goes to the back of the schedule,
+    /// so that a busy loop does not starve the others. Unlimited if `None`.
+    pub quantum: Option<usize>,
+    /// Steps the current turn took in a row, see `quantum`
+    turn_steps: usize,
+    /// How the last step ended, see `step_once`
+    last_event: Option<Event>,
+    /// When files and the deliver output are written out
+    pub flush_mode: FlushMode,
+    /// Steps left before the run stops, unlimited if `None`
+    fuel: Option<u64>,
+    /// Set from anywhere to stop the run, see `stop_token`
+    stop: Arc<AtomicBool>,
+    /// Seed of `rng`, kept so that `reset` replays the same numbers
+    seed: u64,
+    /// `unit.cell_bits` rounded up to a width from `CELL_BITS`
+    cell_bits: u32,
+    /// Source of `Instr::Rand`
+    rng: XorShift,
+    /// Next value of `Instr::Tick`, shared by all elves
+    ticks: Int,
+    /// How many times a monitor may re-trigger itself, see `ECode::MonitorReentrancy`
+    pub monitor_depth_limit: usize,
+    /// High-water marks of the inputs of elves that already fell asleep
+    asleep_high_water: HashMap<(ElfId, Port), usize>,
+    /// Turns of the elves that already fell asleep, (active, idle)
+    asleep_turns: HashMap<ElfId, (u64, u64)>,
+    /// Instructions some elf executed, by room
+    coverage: HashMap<RoomId, HashSet<ElfLine>>,
+    /// How many sheets may wait in a pipe between two elves before the writer has to wait,
+    /// unlimited if `None`. Applies to pipes set up after it is changed.
+    pub pipe_capacity: Option<usize>,
+    /// Values santa sends to named elves right after setting them up, (name, port, value)
+    presets: Vec<(String, Port, Int)>,
+    /// Handler for `Instr::Extension` opcodes
+    extension: Option<Extension>,
+    /// Names elves set up without a name, `ELF_NAMES` if `None`
+    name_provider: Option<NameProvider>,
+    /// Looks up the variables of `ENV(..)` connections, the process environment if `None`
+    env: Option<EnvLookup>,
+    /// How many elves got each name so far, the second `Buddy` is called `Buddy#2`
+    name_counts: HashMap<String, usize>,
+    /// Instruction categories traced by elves, see `Category::mask`
+    trace_mask: u8,
+    /// Receives every step traced, see `set_tracer`
+    tracer: Option<Tracer>,
+    /// Every value elves read from their inputs, when recording
+    input_log: Option<InputLog>,
+    /// Values elves read instead of their inputs, by (elf, port)
+    replay: Option<HashMap<(ElfId, Port), VecDeque<Int>>>,
+    /// Named queues by name, sorted so that they are pumped in the same order every run
+    queues: BTreeMap<Arc<str>, Queue>,
     /// IO files
-    in_files: Vec<OutputPipe<Int>>,
+    in_files: Vec<InFile>,
     out_files: Vec<OutFile>,
+    /// Instructions written out for Chrome's about:tracing, see `enable_chrome_trace`
+    chrome_trace: Option<ChromeTrace>,
 }
 
-#[derive(Debug, Clone)]
+/// Values read by elves in the order they were read, (elf, port, value)
+pub type InputLog = Vec<(ElfId, Port, Int)>;
+
+#[derive(Clone)]
 pub enum Out {
     Std,
     Buffer(String),
+    /// Delivered characters are written as UTF-8, the writer is shared by clones
+    Writer(Arc<Mutex<dyn io::Write + Send>>),
+}
+impl fmt::Debug for Out {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Out::Std => write!(f, "Std"),
+            Out::Buffer(buf) => f.debug_tuple("Buffer").field(buf).finish(),
+            Out::Writer(_) => write!(f, "Writer"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -47,6 +132,36 @@ pub struct Elf {
     inputs: HashMap<Port, InputPipe<Int>>,
     outputs: HashMap<Port, OutputPipe<Int>>,
     finished: bool,
+    /// How many sheets santa put on the stack
+    init_len: usize,
+    /// Lowest stack height the elf has read or popped down to
+    low_water: usize,
+    /// Turns spent working and turns spent waiting on a pipe
+    turns: (u64, u64),
+}
+
+/// When output files and the deliver output get written out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Whenever santa delivers or an elf writes to a pipe, so output shows up right away
+    #[default]
+    Prompt,
+    /// Every 1024 steps and when the run stops, faster for batch runs
+    Batch,
+}
+
+/// An elf falling asleep with more sheets than this gets a warning,
+/// it cannot reach below the top 10 anyway.
+const LEFTOVER_LIMIT: usize = 10;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulePolicy {
+    /// Elves take turns, an elf gives up its turn when it writes or blocks on input.
+    #[default]
+    RoundRobin,
+    /// Each elf runs as far as it can, until it blocks on input or falls asleep.
+    /// Useful for isolating elf behaviour while debugging.
+    SequentialGreedy,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -55,8 +170,10 @@ pub enum RunCommand {
     RunToEnd,
     /// Continue to next breakpoint.
     Continue,
-    /// Step n steps.
+    /// Step n steps, stops early on a breakpoint.
     Step(usize),
+    /// Run to the end, but give up after the given number of steps.
+    RunWithLimit(u64),
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +182,16 @@ pub enum RunOk {
     Stepped(usize),
     /// A breakpoint was hit.
     Breakpoint,
+    /// Result of a RunWithLimit command, the program did not finish in time.
+    LimitReached {
+        steps: u64,
+    },
+    /// The fuel ran out, see `Runtime::add_fuel`. Running again continues where it stopped.
+    OutOfFuel,
+    /// Someone set the stop token, see `Runtime::stop_token`. Running again continues.
+    Interrupted {
+        steps: u64,
+    },
     Done,
 }
 
@@ -80,13 +207,48 @@ pub struct Error<'u> {
 #[derive(Debug, Clone)]
 pub enum ECode {
     InvalidIndex(usize),
+    /// Index popped by `Instr::Pick` is negative
+    NegativeIndex(Int),
+    /// Sleeve slot out of range
+    InvalidSlot(u8),
     InvalidInstr,
     DivisionByZero,
+    /// Result of `a op b` does not fit into `Int`, negation is `0 - b`
+    Overflow(Op, Int, Int),
+    /// Shift amount is negative or too large
+    InvalidShift(Int),
+    /// Upper bound of `Instr::Rand` is not positive
+    InvalidBound(Int),
+    /// A file could not be opened
+    Io(Arc<str>, Arc<io::Error>),
+    /// Environment variable is not set or is not a number
+    Env(Arc<str>),
+    /// Everyone waits for input that will never come, lists (who, port)
+    Deadlock(Vec<(String, Port)>),
+    /// A monitor kept re-triggering itself through its own sends
+    MonitorReentrancy,
+    /// Santa receives from a port nobody monitors
+    NoSuchMonitor {
+        elf: ElfId,
+        port: Port,
+    },
+    /// Santa connects a pipe to an elf that already fell asleep for good
+    ElfGone(ElfId),
+    /// A monitor block ran again for a port it already watches, e.g. inside `repeat`
+    DuplicateMonitor {
+        elf: ElfId,
+        port: Port,
+    },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Turn {
-    Santa { ip: usize, until: usize },
+    /// `monitor` is the monitor line when running its handler
+    Santa {
+        ip: usize,
+        until: usize,
+        monitor: Option<SantaLine>,
+    },
     Elf(ElfId),
 }
 impl Turn {
@@ -98,14 +260,55 @@ impl Turn {
     }
 }
 
+/// How a step ended the turn, if it did
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Event {
+pub enum Event {
+    /// Waiting on a pipe, others get their turn
     Yield,
+    /// Time passes without waiting on anything, others get their turn
+    Tick,
+    /// Fell asleep or finished, the turn leaves the schedule
     Dequeue,
     Breakpoint,
+    /// Wrote to an output port
     Write(Port),
 }
 
+/// What happened in one step, see `Runtime::step_once`
+#[derive(Debug, Clone)]
+pub struct StepInfo {
+    /// Elf that made the step, `None` for santa
+    pub elf: Option<ElfId>,
+    pub ip: usize,
+    /// What ran, `None` when santa was done with his ToDos or a monitor handler
+    pub code: Option<TraceCode>,
+    pub event: Option<Event>,
+}
+
+/// How `Runtime::step_once` ended
+#[derive(Debug, Clone)]
+pub enum StepOk {
+    /// One step was made
+    Stepped(StepInfo),
+    /// The fuel ran out, see `Runtime::add_fuel`. Stepping again continues where it stopped.
+    OutOfFuel,
+    /// Someone set the stop token, see `Runtime::stop_token`. Stepping again continues.
+    Interrupted,
+    Done,
+}
+
+/// How `Runtime::run_until_deliver` ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliverOk {
+    /// Text santa delivered, a single char unless he delivered a string
+    Delivered(String),
+    /// The fuel ran out, see `Runtime::add_fuel`. Running again continues where it stopped.
+    OutOfFuel,
+    /// Someone set the stop token, see `Runtime::stop_token`. Running again continues.
+    Interrupted,
+    Done,
+}
+
 struct OutFile {
     pipe: InputPipe<Int>,
     writer: Box<dyn io::Write>,
@@ -116,6 +319,200 @@ impl fmt::Debug for OutFile {
     }
 }
 
+/// JSON array of trace events, every instruction of an elf is a complete (`"ph":"X"`) event.
+/// The array is closed when dropped, Chrome loads the file even without it.
+struct ChromeTrace {
+    writer: Box<dyn io::Write>,
+    /// Steps made so far, the timestamp of the next event
+    step: u64,
+    empty: bool,
+}
+impl fmt::Debug for ChromeTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChromeTrace")
+            .field("step", &self.step)
+            .finish()
+    }
+}
+impl ChromeTrace {
+    fn event(&mut self, elf: &Elf, code: &Instr) -> io::Result<()> {
+        let sep = if self.empty { "" } else { "," };
+        self.empty = false;
+        let name = json_string(&format!("{code:?}"));
+        write!(
+            self.writer,
+            "{sep}\n{{\"ph\":\"X\",\"name\":{name},\"pid\":{},\"tid\":0,\"ts\":{},\"dur\":1}}",
+            elf.id, self.step
+        )
+    }
+}
+impl Drop for ChromeTrace {
+    fn drop(&mut self) {
+        _ = writeln!(self.writer, "\n]").and_then(|_| self.writer.flush());
+    }
+}
+
+/// Named queue, elves write to `input`, values are passed on in `pump_queues`
+#[derive(Debug)]
+struct Queue {
+    input: InputPipe<Int>,
+    /// One pipe per reading elf, they get the values in turns
+    readers: Vec<OutputPipe<Int>>,
+    next_reader: usize,
+}
+
+/// How many bytes of an input file are read at once
+const READ_CHUNK: usize = 1 << 12;
+
+/// File read lazily into an elf's input, a chunk at a time
+struct InFile {
+    file: Arc<str>,
+    dst: (ElfId, Port),
+    pipe: OutputPipe<Int>,
+    reader: Box<dyn io::Read>,
+    /// Start of a character split between two chunks
+    carry: Vec<u8>,
+}
+impl fmt::Debug for InFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InFile")
+            .field("file", &self.file)
+            .field("dst", &self.dst)
+            .finish()
+    }
+}
+
+impl InFile {
+    /// Send the next chunk of characters, returns false at the end of the file
+    fn feed(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = self.reader.read(&mut chunk)?;
+        if n == 0 {
+            return match self.carry.is_empty() {
+                true => Ok(false),
+                false => Err(io::ErrorKind::InvalidData.into()),
+            };
+        }
+
+        self.carry.extend_from_slice(&chunk[..n]);
+        let valid = match std::str::from_utf8(&self.carry) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_some() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            Err(e) => e.valid_up_to(),
+        };
+        let text = std::str::from_utf8(&self.carry[..valid]).unwrap();
+        for c in text.chars() {
+            self.pipe.write(c as Int);
+        }
+        self.carry.drain(..valid);
+        Ok(true)
+    }
+}
+
+/// One step of santa or an elf, as passed to the tracer
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    /// Elf that made the step, `None` for santa
+    pub elf: Option<ElfId>,
+    pub ip: usize,
+    pub code: TraceCode,
+    /// Top of the elf's stack after the step, for santa the value of the line
+    pub top: Option<Int>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TraceCode {
+    Elf(Instr),
+    Santa(SantaCode),
+}
+
+pub type TraceHandler = Box<dyn FnMut(TraceEvent)>;
+
+struct Tracer {
+    handler: TraceHandler,
+}
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+pub type ExtensionHandler = Box<dyn FnMut(&mut ElfView, u16) -> Result<(), ECode>>;
+
+struct Extension {
+    handler: ExtensionHandler,
+}
+impl fmt::Debug for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extension").finish_non_exhaustive()
+    }
+}
+
+struct NameProvider {
+    provider: Box<dyn FnMut(ElfId) -> String>,
+}
+impl fmt::Debug for NameProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NameProvider").finish_non_exhaustive()
+    }
+}
+
+/// Value of an environment variable, `None` when it is not set
+pub type EnvHandler = Box<dyn Fn(&str) -> Option<String>>;
+
+struct EnvLookup {
+    lookup: EnvHandler,
+}
+impl fmt::Debug for EnvLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnvLookup").finish_non_exhaustive()
+    }
+}
+
+/// What an extension handler may touch on the elf executing it.
+pub struct ElfView<'e> {
+    name: &'e str,
+    stack: &'e mut Vec<Int>,
+    sleeve: &'e mut [Int; 10],
+}
+impl ElfView<'_> {
+    pub fn name(&self) -> &str {
+        self.name
+    }
+    pub fn stack(&self) -> &[Int] {
+        self.stack
+    }
+    /// Value `from_top` sheets below the top (0 = top)
+    pub fn top(&self, from_top: usize) -> Result<Int, ECode> {
+        match from_top < self.stack.len() {
+            true => Ok(self.stack[self.stack.len() - from_top - 1]),
+            false => Err(ECode::InvalidIndex(from_top)),
+        }
+    }
+    pub fn push(&mut self, value: Int) {
+        self.stack.push(value);
+    }
+    pub fn pop(&mut self) -> Result<Int, ECode> {
+        self.stack.pop().ok_or(ECode::InvalidIndex(0))
+    }
+    pub fn sleeve(&self, slot: u8) -> Result<Int, ECode> {
+        self.sleeve
+            .get(slot as usize)
+            .copied()
+            .ok_or(ECode::InvalidSlot(slot))
+    }
+    pub fn set_sleeve(&mut self, slot: u8, value: Int) -> Result<(), ECode> {
+        let cell = self
+            .sleeve
+            .get_mut(slot as usize)
+            .ok_or(ECode::InvalidSlot(slot))?;
+        *cell = value;
+        Ok(())
+    }
+}
+
 #[rustfmt::skip]
 const ELF_NAMES: [&str; 256] = [
     "Alabaster", "Archibald", "Applejack", "Amberglow", "Astra", "Auburn", "Aurora", "Amity", "Aurelian", "Azura", "Aspen",
@@ -146,7 +543,21 @@ const ELF_NAMES: [&str; 256] = [
 ];
 
 impl<'u> Runtime<'u> {
+    /// Runtime with random numbers seeded from the clock, see `new_seeded`
     pub fn new(unit: &'u Unit) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        Self::new_seeded(unit, now.map_or(0, |d| d.as_nanos() as u64))
+    }
+
+    /// Runtime whose `Instr::Rand` gives the same numbers every run with the same `seed`.
+    pub fn new_seeded(unit: &'u Unit, seed: u64) -> Self {
+        // the field is public, a unit may not come from the builder
+        let wanted = unit.cell_bits;
+        let cell_bits = CELL_BITS.into_iter().find(|&bits| bits >= wanted);
+        let cell_bits = cell_bits.unwrap_or(64);
+        if cell_bits != wanted {
+            log::warn!("Sheets cannot be {wanted} bits wide, using {cell_bits}");
+        }
         Self {
             unit,
             santa_result: vec![0; unit.santa.len()],
@@ -155,27 +566,312 @@ impl<'u> Runtime<'u> {
             schedule: VecDeque::from([Turn::Santa {
                 ip: 0,
                 until: unit.santa.len(),
+                monitor: None,
             }]),
             monitors: Default::default(),
+            monitor_depth: Default::default(),
+            monitor_sent: Default::default(),
+            santa_outputs: Default::default(),
             output: Out::Std,
+            policy: SchedulePolicy::default(),
+            quantum: None,
+            turn_steps: 0,
+            last_event: None,
+            flush_mode: FlushMode::default(),
+            fuel: None,
+            stop: Default::default(),
+            seed,
+            cell_bits,
+            rng: XorShift::new(seed),
+            ticks: 0,
+            monitor_depth_limit: 1 << 10,
+            pipe_capacity: None,
+            asleep_high_water: Default::default(),
+            asleep_turns: Default::default(),
+            coverage: Default::default(),
+            presets: Vec::new(),
+            extension: None,
+            name_provider: None,
+            env: None,
+            name_counts: HashMap::new(),
+            trace_mask: Category::ALL,
+            tracer: None,
+            input_log: None,
+            replay: None,
 
+            queues: Default::default(),
             in_files: Vec::new(),
             out_files: Vec::new(),
+            chrome_trace: None,
         }
     }
 
+    pub fn elf(&self, id: ElfId) -> Option<&Elf> {
+        self.elves.get(&id)
+    }
+
     pub fn reset(&mut self) {
-        *self = Self::new(self.unit);
+        let policy = self.policy;
+        let quantum = self.quantum;
+        let flush_mode = self.flush_mode;
+        let fuel = self.fuel;
+        let stop = self.stop.clone();
+        let seed = self.seed;
+        let monitor_depth_limit = self.monitor_depth_limit;
+        let pipe_capacity = self.pipe_capacity;
+        let extension = self.extension.take();
+        let name_provider = self.name_provider.take();
+        let env = self.env.take();
+        let trace_mask = self.trace_mask;
+        let tracer = self.tracer.take();
+        let output = std::mem::replace(&mut self.output, Out::Std);
+        let presets = std::mem::take(&mut self.presets);
+        let input_log = self.input_log.take();
+        let replay = self.replay.take();
+        *self = Self::new_seeded(self.unit, seed);
+        self.input_log = input_log;
+        self.replay = replay;
+        self.output = output;
+        self.presets = presets;
+        self.policy = policy;
+        self.quantum = quantum;
+        self.flush_mode = flush_mode;
+        self.fuel = fuel;
+        self.stop = stop;
+        self.monitor_depth_limit = monitor_depth_limit;
+        self.pipe_capacity = pipe_capacity;
+        self.extension = extension;
+        self.name_provider = name_provider;
+        self.env = env;
+        self.trace_mask = trace_mask;
+        self.tracer = tracer;
+    }
+
+    /// Where santa delivers to, file outputs are not affected.
+    pub fn set_output(&mut self, out: Out) {
+        self.output = out;
+    }
+
+    /// Text delivered to `Out::Buffer` so far, the buffer is left empty.
+    /// `None` when santa delivers elsewhere.
+    pub fn take_output(&mut self) -> Option<String> {
+        match &mut self.output {
+            Out::Buffer(buf) => Some(std::mem::take(buf)),
+            Out::Std | Out::Writer(_) => None,
+        }
+    }
+
+    /// Have santa send `value` to `port` of the elf called `elf_name` as soon as it is set up.
+    pub fn preset_input(&mut self, elf_name: impl Into<String>, port: Port, value: Int) {
+        self.presets.push((elf_name.into(), port, value));
+    }
+
+    /// Install a handler for the reserved `Instr::Extension(opcode)` instruction.
+    /// Elves hitting an extension opcode without a handler fail with `ECode::InvalidInstr`.
+    pub fn set_extension_handler(&mut self, handler: ExtensionHandler) {
+        self.extension = Some(Extension { handler });
+    }
+
+    /// Name the elves santa sets up without a name with `provider`, instead of the built-in list.
+    pub fn set_name_provider(&mut self, provider: Box<dyn FnMut(ElfId) -> String>) {
+        self.name_provider = Some(NameProvider { provider });
+    }
+
+    /// Read the variables of `ENV("VAR")` connections with `lookup` instead of the environment.
+    pub fn set_env_lookup(&mut self, lookup: EnvHandler) {
+        self.env = Some(EnvLookup { lookup });
+    }
+
+    /// Pass every step to `handler`, where the trace log would show it.
+    /// Respects `trace_categories` for elf instructions.
+    pub fn set_tracer(&mut self, handler: TraceHandler) {
+        self.tracer = Some(Tracer { handler });
+    }
+
+    /// Only trace elf instructions whose category is in `mask`,
+    /// e.g. `Category::IO.mask() | Category::Control.mask()`.
+    pub fn trace_categories(&mut self, mask: u8) {
+        self.trace_mask = mask;
+    }
+
+    /// Allow `n` more steps, every santa line and elf instruction burns one.
+    /// The first call switches from unlimited running to the fuel model.
+    pub fn add_fuel(&mut self, n: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(n));
+    }
+
+    /// Steps left, `None` when running is not limited by fuel
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Seed of the random numbers, pass it to `new_seeded` to get them again
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Start or stop recording every value elves read, see `input_log`.
+    /// The log survives `reset`, so it is kept when a run fails.
+    pub fn record_inputs(&mut self, record: bool) {
+        self.input_log = record.then(|| self.input_log.take().unwrap_or_default());
+    }
+
+    /// Values read so far while recording
+    pub fn input_log(&self) -> &[(ElfId, Port, Int)] {
+        self.input_log.as_deref().unwrap_or_default()
+    }
+
+    /// Feed elves the values of a recorded `log` instead of reading their inputs.
+    /// An elf whose recorded values ran out reads as if the port was closed.
+    pub fn replay_inputs(&mut self, log: InputLog) {
+        let mut replay: HashMap<_, VecDeque<_>> = HashMap::new();
+        for (elf, port, value) in log {
+            replay.entry((elf, port)).or_default().push_back(value);
+        }
+        self.replay = Some(replay);
+    }
+
+    /// Write every instruction elves do into `path`, in the trace event format of Chrome's
+    /// about:tracing and Perfetto. Each elf shows up as a process, one step lasts a microsecond.
+    pub fn enable_chrome_trace(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer: Box<dyn io::Write> = Box::new(io::BufWriter::new(fs::File::create(path)?));
+        write!(writer, "[")?;
+        self.chrome_trace = Some(ChromeTrace {
+            writer,
+            step: 0,
+            empty: true,
+        });
+        Ok(())
+    }
+
+    /// Most sheets that were ever waiting at once in each elf input, by (elf, port).
+    /// Useful for picking `pipe_capacity`.
+    pub fn pipe_high_water(&self) -> HashMap<(ElfId, Port), usize> {
+        let mut marks = self.asleep_high_water.clone();
+        for (id, elf) in &self.elves {
+            for (port, input) in &elf.inputs {
+                marks.insert((*id, *port), input.high_water());
+            }
+        }
+        marks
+    }
+
+    /// Turns each elf spent working and turns it spent waiting on a pipe, (active, idle).
+    /// An elf idling a lot is starved by whoever feeds it.
+    pub fn elf_utilization(&self) -> HashMap<ElfId, (u64, u64)> {
+        let mut turns = self.asleep_turns.clone();
+        turns.extend(self.elves.iter().map(|(id, elf)| (*id, elf.turns)));
+        turns
+    }
+
+    /// Instructions of the room that ran at least once, `Room::ip_to_tile` tells their tiles.
+    /// The ones missing were never tested by this run.
+    pub fn coverage(&self, room: RoomId) -> HashSet<ElfLine> {
+        self.coverage.get(&room).cloned().unwrap_or_default()
+    }
+
+    /// Flag that stops `run` before its next step once set, e.g. from a Ctrl-C handler.
+    /// The run clears it when it stops, the token stays the same after `reset`.
+    pub fn stop_token(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Make exactly one step and tell who made it, what they did and how it ended.
+    pub fn step_once(&mut self) -> Result<StepOk, Error<'u>> {
+        let Some(&turn) = self.schedule.front() else {
+            return Ok(StepOk::Done);
+        };
+        let (elf, ip, code) = match turn {
+            Turn::Santa { ip, until, .. } => {
+                let code = self.unit.santa.get(ip).filter(|_| ip < until);
+                (None, ip, code.cloned().map(TraceCode::Santa))
+            }
+            Turn::Elf(id) => {
+                let elf = &self.elves[&id];
+                let program = &self.unit.rooms[elf.room].elf_program;
+                let code = program.get(elf.ip).copied().unwrap_or(Instr::Hammock);
+                (Some(id), elf.ip, Some(TraceCode::Elf(code)))
+            }
+        };
+        match self.run(RunCommand::Step(1))? {
+            RunOk::OutOfFuel => Ok(StepOk::OutOfFuel),
+            RunOk::Interrupted { .. } => Ok(StepOk::Interrupted),
+            RunOk::Done => Ok(StepOk::Done),
+            _ => Ok(StepOk::Stepped(StepInfo {
+                elf,
+                ip,
+                code,
+                event: self.last_event,
+            })),
+        }
+    }
+
+    /// Step until santa delivers a character or a string and return it,
+    /// it is written out as usual.
+    pub fn run_until_deliver(&mut self) -> Result<DeliverOk, Error<'u>> {
+        loop {
+            let info = match self.step_once()? {
+                StepOk::Stepped(info) => info,
+                StepOk::OutOfFuel => return Ok(DeliverOk::OutOfFuel),
+                StepOk::Interrupted => return Ok(DeliverOk::Interrupted),
+                StepOk::Done => return Ok(DeliverOk::Done),
+            };
+            match info.code {
+                Some(TraceCode::Santa(SantaCode::Deliver(line))) => {
+                    let c = to_char(self.santa_result[line] as Int);
+                    return Ok(DeliverOk::Delivered(c.to_string()));
+                }
+                Some(TraceCode::Santa(SantaCode::DeliverStr(text))) => {
+                    return Ok(DeliverOk::Delivered(text.to_string()));
+                }
+                _ => {}
+            }
+        }
     }
 
-    pub fn run(&mut self, cmd: RunCommand) -> Result<RunOk, Error> {
+    /// Rough bytes each awake elf takes: its stack, sleeve and the sheets waiting in its inputs.
+    pub fn memory_report(&self) -> HashMap<ElfId, usize> {
+        let footprint = |elf: &Elf| {
+            let pipes: usize = elf.inputs.values().map(InputPipe::footprint).sum();
+            elf.stack.capacity() * size_of::<Int>() + size_of_val(&*elf.sleeve) + pipes
+        };
+        self.elves
+            .iter()
+            .map(|(id, elf)| (*id, footprint(elf)))
+            .collect()
+    }
+
+    /// Run until `cmd` says to stop, the program ends or fails.
+    ///
+    /// The order of turns depends only on the unit and the runtime settings,
+    /// so the same unit always delivers the same output:
+    /// - Santa starts, elves join the back of the schedule in the order he sets them up.
+    /// - The front turn runs step by step until it writes, waits on a pipe or santa waits,
+    ///   then it goes to the back. With `SequentialGreedy`, writing keeps the turn.
+    /// - A monitor handler cuts in at the front right after its port gets a sheet.
+    /// - An elf that takes `quantum` steps in a row goes to the back as well.
+    /// - Turns that fall asleep or finish leave the schedule.
+    pub fn run(&mut self, cmd: RunCommand) -> Result<RunOk, Error<'u>> {
         let mut last = None;
         let mut steps = 0u64;
+        let mut idle = 0usize;
 
         let result = loop {
             let Some(mut next) = self.schedule.pop_front() else {
                 break Ok(RunOk::Done);
             };
+            if self.stop.swap(false, Ordering::Relaxed) {
+                self.schedule.push_front(next);
+                break Ok(RunOk::Interrupted { steps });
+            }
+            match &mut self.fuel {
+                Some(0) => {
+                    self.schedule.push_front(next);
+                    break Ok(RunOk::OutOfFuel);
+                }
+                Some(fuel) => *fuel -= 1,
+                None => {}
+            }
             if Some(next) != last {
                 match next {
                     Turn::Elf(id) => log::debug!("Scheduling {next:?} {:?}", self.elves[&id].name),
@@ -185,28 +881,18 @@ impl<'u> Runtime<'u> {
             }
 
             let result = match &mut next {
-                Turn::Santa { ip, until } => self.step_santa(ip, until),
-                Turn::Elf(id) => self.step_elf(*id),
+                Turn::Santa { ip, until, monitor } => self.step_santa(ip, until, *monitor),
+                Turn::Elf(id) => {
+                    self.pump_queues(false);
+                    self.feed_in_files(Some(*id))
+                        .and_then(|_| self.step_elf(*id))
+                }
             };
 
             let evt = match result {
                 Ok(ev) => ev,
                 Err(ecode) => {
-                    let (ip, stack, room) = match next {
-                        Turn::Santa { ip, .. } => (ip, vec![], None),
-                        Turn::Elf(id) => {
-                            let elf = &self.elves[&id];
-                            (elf.ip, elf.stack.clone(), Some(elf.room))
-                        }
-                    };
-                    let error = Error {
-                        unit: self.unit,
-                        ip,
-                        room,
-                        culprit: next,
-                        code: ecode,
-                        stack,
-                    };
+                    let error = self.error(next, ecode);
                     self.reset();
                     break Err(error);
                 }
@@ -215,28 +901,98 @@ impl<'u> Runtime<'u> {
             if evt.is_some() {
                 log::trace!("evt={evt:?}");
             }
+            self.last_event = evt;
+
+            let keeps_turn = evt.is_none() && matches!(next, Turn::Elf(_));
+            self.turn_steps = if keeps_turn { self.turn_steps + 1 } else { 0 };
+            let preempted = self.quantum.is_some_and(|n| self.turn_steps >= n);
+            if preempted {
+                self.turn_steps = 0;
+            }
 
             // requeue
             match evt {
                 Some(Event::Dequeue) => match next {
                     Turn::Elf(id) => {
-                        self.elves.remove(&id);
+                        if let Some(elf) = self.elves.remove(&id) {
+                            let marks = elf.inputs.iter().map(|(p, i)| ((id, *p), i.high_water()));
+                            self.asleep_high_water.extend(marks);
+                            self.asleep_turns.insert(id, elf.turns);
+                        }
+                    }
+                    Turn::Santa {
+                        monitor: Some(mon), ..
+                    } => {
+                        // a handler that did not feed anything back breaks the chain
+                        let sent = self.monitor_sent.remove(&mon);
+                        if !sent {
+                            self.monitor_depth.remove(&mon);
+                        }
                     }
                     _ => {}
                 },
-                Some(Event::Yield | Event::Write(_)) => self.schedule.push_back(next),
+                Some(Event::Write(_)) if self.policy == SchedulePolicy::SequentialGreedy => {
+                    self.schedule.push_front(next)
+                }
+                Some(Event::Yield | Event::Tick | Event::Write(_)) => self.schedule.push_back(next),
+                _ if preempted => self.schedule.push_back(next),
                 _ => self.schedule.push_front(next), // else repeat the same `next`
             }
 
+            // if every scheduled turn yielded in a row, nobody can make progress
+            match evt {
+                Some(Event::Yield) => idle += 1,
+                _ => idle = 0,
+            }
+            if idle > 0 && idle >= self.schedule.len() {
+                // santa is done sending, close his pipes and see if that unblocks anyone
+                let santa_waits = self
+                    .schedule
+                    .iter()
+                    .any(|t| matches!(t, Turn::Santa { .. }));
+                if !santa_waits && !self.santa_outputs.is_empty() {
+                    self.santa_outputs.clear();
+                    idle = 0;
+                    continue;
+                }
+                // writers of a queue are gone and santa will not add new ones
+                if self.pump_queues(!santa_waits) {
+                    idle = 0;
+                    continue;
+                }
+                match self.feed_in_files(None) {
+                    Ok(true) => {
+                        idle = 0;
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(ecode) => {
+                        let error = self.error(next, ecode);
+                        self.reset();
+                        break Err(error);
+                    }
+                }
+                let error = self.error(next, ECode::Deadlock(self.stuck_turns()));
+                self.reset();
+                break Err(error);
+            }
+
             // event side effect
             match evt {
-                Some(Event::Breakpoint) => todo!("breakpoint"),
                 Some(Event::Write(port)) => {
                     let key = (next.unwrap_elfid(), port);
                     if let Some(mon) = self.monitors.get(&key) {
+                        let depth = self.monitor_depth.entry(mon.1).or_default();
+                        *depth += 1;
+                        if *depth > self.monitor_depth_limit {
+                            let error = self.error(next, ECode::MonitorReentrancy);
+                            self.reset();
+                            break Err(error);
+                        }
                         self.schedule.push_front(Turn::Santa {
                             ip: mon.1 + 1,
-                            until: self.unit.santa[mon.1].unwrap_monitor().1,
+                            until: mon.1 + self.unit.santa[mon.1].unwrap_monitor().1,
+                            monitor: Some(mon.1),
                         });
                     }
                 }
@@ -244,14 +1000,24 @@ impl<'u> Runtime<'u> {
             }
 
             steps += 1;
-            if steps % (1 << 10) == 0 {
+            if let Some(trace) = &mut self.chrome_trace {
+                trace.step += 1;
+            }
+            let wrote = matches!(evt, Some(Event::Write(_)));
+            if (wrote && self.flush_mode == FlushMode::Prompt) || steps % (1 << 10) == 0 {
                 self.flush_outs();
             }
 
             match cmd {
+                RunCommand::Continue | RunCommand::Step(_) if evt == Some(Event::Breakpoint) => {
+                    break Ok(RunOk::Breakpoint);
+                }
                 RunCommand::Step(n) if steps as usize >= n => {
                     return Ok(RunOk::Stepped(steps as usize));
                 }
+                RunCommand::RunWithLimit(limit) if steps >= limit => {
+                    break Ok(RunOk::LimitReached { steps });
+                }
                 _ => {}
             }
         };
@@ -260,8 +1026,51 @@ impl<'u> Runtime<'u> {
         result
     }
 
-    fn step_santa(&mut self, santa_ip: &mut usize, until: &usize) -> Result<Option<Event>, ECode> {
-        let Some(code) = self.unit.santa.get(*santa_ip) else {
+    fn error(&self, culprit: Turn, code: ECode) -> Error<'u> {
+        let (ip, stack, room) = match culprit {
+            Turn::Santa { ip, .. } => (ip, vec![], None),
+            Turn::Elf(id) => {
+                let elf = &self.elves[&id];
+                (elf.ip, elf.stack.clone(), Some(elf.room))
+            }
+        };
+        Error {
+            unit: self.unit,
+            ip,
+            room,
+            culprit,
+            code,
+            stack,
+        }
+    }
+
+    /// Describe who is waiting on which port, for every scheduled turn.
+    fn stuck_turns(&self) -> Vec<(String, Port)> {
+        let describe = |turn: &Turn| match *turn {
+            Turn::Santa { ip, .. } => match self.unit.santa.get(ip) {
+                Some(SantaCode::Receive(_, port)) => Some(("Santa".to_string(), *port)),
+                _ => None,
+            },
+            Turn::Elf(id) => {
+                let elf = &self.elves[&id];
+                match self.unit.rooms[elf.room].elf_program.get(elf.ip) {
+                    Some(Instr::In(port) | Instr::Out(port)) => Some((elf.name.clone(), *port)),
+                    _ => None,
+                }
+            }
+        };
+        self.schedule.iter().filter_map(describe).collect()
+    }
+
+    fn step_santa(
+        &mut self,
+        santa_ip: &mut usize,
+        until: &usize,
+        monitor: Option<SantaLine>,
+    ) -> Result<Option<Event>, ECode> {
+        // a monitor handler ends with its block
+        let code = self.unit.santa.get(*santa_ip);
+        let Some(code) = code.filter(|_| *santa_ip < *until) else {
             return Ok(Some(Event::Dequeue));
         };
 
@@ -278,38 +1087,63 @@ impl<'u> Runtime<'u> {
                 self.santa_result[ip] = *n as usize;
                 None
             }
-            SantaCode::SetupElf { name, room, init_stack } => {
+            SantaCode::SetupElf {
+                name,
+                room,
+                entry,
+                init_stack,
+            } => {
+                let id = self.next_elf_id;
+                let name = match (name, &mut self.name_provider) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(NameProvider { provider })) => provider(id),
+                    (None, None) => ELF_NAMES[id % ELF_NAMES.len()].to_string(),
+                };
+                // presets go to every elf with the name, traces and errors tell them apart
+                let presets = self.presets.iter().filter(|(preset, ..)| *preset == name);
+                let presets: Vec<_> = presets.map(|&(_, port, value)| (port, value)).collect();
+                let count = self.name_counts.entry(name.clone()).or_default();
+                *count += 1;
+                let name = match *count {
+                    1 => name,
+                    n => format!("{name}#{n}"),
+                };
                 let new = Elf {
-                    ip: 0,
+                    ip: *entry,
                     room: *room,
-                    id: self.next_elf_id,
-                    name: name.clone().unwrap_or_else(|| {
-                        ELF_NAMES[self.next_elf_id % ELF_NAMES.len()].to_string()
-                    }),
+                    id,
+                    name,
                     stack: init_stack.iter().map(|&it| self.santa_result[it] as Int).collect(),
                     sleeve: Box::new([0; 10]),
                     inputs: Default::default(),
                     outputs: Default::default(),
                     finished: false,
+                    init_len: init_stack.len(),
+                    low_water: init_stack.len(),
+                    turns: (0, 0),
                 };
                 self.next_elf_id += 1;
 
                 self.schedule.push_back(Turn::Elf(new.id));
                 self.santa_result[ip] = new.id;
+
+                let elf_id = new.id;
                 self.elves.insert(new.id, new);
+                for (port, value) in presets {
+                    self.send(elf_id, port, value);
+                }
                 None
             }
             SantaCode::Connect { src, dst } => {
                 let src_eid = self.santa_result[src.0];
                 let dst_eid = self.santa_result[dst.0];
 
-                if let [Some(src_elf), Some(dst_elf)] =
-                    self.elves.get_disjoint_mut([&src_eid, &dst_eid])
-                {
-                    let mut output = src_elf.ensure_output(src.1);
-                    dst_elf.ensure_input(dst.1, &mut output);
-                } else if src_eid == dst_eid {
-                    let elf = self.elves.get_mut(&src_eid).unwrap();
+                // `get_disjoint_mut` panics when both keys are the same elf
+                if src_eid == dst_eid {
+                    let elf = self
+                        .elves
+                        .get_mut(&src_eid)
+                        .ok_or(ECode::ElfGone(src_eid))?;
                     let port = src.1;
                     let output = elf
                         .outputs
@@ -317,31 +1151,94 @@ impl<'u> Runtime<'u> {
                         .or_insert_with(|| OutputPipe::default());
 
                     let port = dst.1;
-                    elf.inputs
+                    let input = elf
+                        .inputs
                         .entry(port)
                         .and_modify(|input| input.connect(output))
                         .or_insert_with(|| InputPipe::new_connected(output));
+                    if let Some(capacity) = self.pipe_capacity {
+                        input.set_capacity(capacity);
+                    }
+                } else if let [Some(src_elf), Some(dst_elf)] =
+                    self.elves.get_disjoint_mut([&src_eid, &dst_eid])
+                {
+                    let mut output = src_elf.ensure_output(src.1);
+                    let input = dst_elf.ensure_input(dst.1, &mut output);
+                    if let Some(capacity) = self.pipe_capacity {
+                        input.set_capacity(capacity);
+                    }
                 } else {
-                    panic!("SantaCode::Connect {{ {src:?}, {dst:?} }}")
+                    let gone = match self.elves.contains_key(&src_eid) {
+                        true => dst_eid,
+                        false => src_eid,
+                    };
+                    return Err(ECode::ElfGone(gone));
                 }
                 None
             }
             SantaCode::OpenRead { file, dst } => {
-                let content = fs::read_to_string(file.as_ref()).unwrap();
+                let opened =
+                    fs::File::open(file.as_ref()).map_err(|e| ECode::Io(file.clone(), e.into()))?;
+                let elfid = self.santa_result[dst.0];
+                if let Some(elf) = self.elves.get_mut(&elfid) {
+                    let mut pipe = OutputPipe::new();
+                    elf.ensure_input(dst.1, &mut pipe);
+                    // filled on demand, see `feed_in_files`
+                    self.in_files.push(InFile {
+                        file: file.clone(),
+                        dst: (elfid, dst.1),
+                        pipe,
+                        reader: Box::new(opened),
+                        carry: Vec::new(),
+                    });
+                } else {
+                    panic!("bug: unknown elf {elfid}");
+                }
+                None
+            }
+            SantaCode::ReadEnv { var, dst } => {
+                let text = match &self.env {
+                    Some(EnvLookup { lookup }) => lookup(var),
+                    None => std::env::var(var.as_ref()).ok(),
+                };
+                let value = text
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or_else(|| ECode::Env(var.clone()))?;
                 let elfid = self.santa_result[dst.0];
                 if let Some(elf) = self.elves.get_mut(&elfid) {
-                    // this will produce closed pipe
+                    // primed once, then the pipe is closed
                     let input = elf.ensure_input(dst.1, &mut OutputPipe::new());
-                    for c in content.chars() {
-                        input.write_direct(c as Int);
-                    }
+                    input.write_direct(value);
                 } else {
                     panic!("bug: unknown elf {elfid}");
                 }
                 None
             }
+            SantaCode::ToQueue { src, queue } => {
+                let elfid = self.santa_result[src.0];
+                let queue = self.queues.entry(queue.clone()).or_insert_with(Queue::new);
+                if let Some(elf) = self.elves.get_mut(&elfid) {
+                    elf.ensure_output(src.1).connect(&mut queue.input);
+                }
+                None
+            }
+            SantaCode::FromQueue { queue, dst } => {
+                let elfid = self.santa_result[dst.0];
+                let queue = self.queues.entry(queue.clone()).or_insert_with(Queue::new);
+                if let Some(elf) = self.elves.get_mut(&elfid) {
+                    let mut pipe = OutputPipe::new();
+                    let input = elf.ensure_input(dst.1, &mut pipe);
+                    if let Some(capacity) = self.pipe_capacity {
+                        input.set_capacity(capacity);
+                    }
+                    queue.readers.push(pipe);
+                }
+                None
+            }
             SantaCode::OpenWrite { src, file } => {
-                let wr = io::BufWriter::new(fs::File::create(&**file).expect(&file));
+                let created = fs::File::create(&**file);
+                let wr =
+                    io::BufWriter::new(created.map_err(|e| ECode::Io(file.clone(), e.into()))?);
                 let elfid = self.santa_result[src.0];
                 if let Some(elf) = self.elves.get_mut(&elfid) {
                     let file_pipe = InputPipe::new_connected(elf.ensure_output(src.1));
@@ -363,17 +1260,23 @@ impl<'u> Runtime<'u> {
                     .unwrap_or_else(|| panic!("{port:?}, {block_len}"));
                 let output = elf.ensure_output(port);
 
+                // translation rejects two monitor blocks on one port, not one block running twice
+                if self.monitors.contains_key(&(elf_id, port)) {
+                    return Err(ECode::DuplicateMonitor { elf: elf_id, port });
+                }
                 let v = (InputPipe::new_connected(output), ip);
-                let conflict = self.monitors.insert((elf_id, port), v);
-
-                assert!(conflict.is_none(), "port=({elf_id}, {port})");
+                self.monitors.insert((elf_id, port), v);
                 next_ip = ip + *block_len;
                 None
             }
             SantaCode::Receive(elf_line, port) => {
                 let elf_id = self.santa_result[*elf_line];
 
-                let monitor = self.monitors.get_mut(&(elf_id, *port)).unwrap();
+                let no_monitor = ECode::NoSuchMonitor {
+                    elf: elf_id,
+                    port: *port,
+                };
+                let monitor = self.monitors.get_mut(&(elf_id, *port)).ok_or(no_monitor)?;
 
                 match monitor.0.try_read() {
                     Err(InputError::Closed) => Some(Event::Dequeue), // reading closed input hangs forever
@@ -387,19 +1290,77 @@ impl<'u> Runtime<'u> {
                     }
                 }
             }
-            SantaCode::Send(_, _, _) => todo!(),
-            SantaCode::Deliver(line) => {
-                let c = self.santa_result[*line] as u8 as char;
-                match &mut self.output {
-                    Out::Std => print!("{}", c),
-                    Out::Buffer(buf) => buf.push(c),
+            SantaCode::Send(elf_line, port, value) => {
+                let elf_id = self.santa_result[*elf_line];
+                let value = self.santa_result[*value] as Int;
+                self.send(elf_id, *port, value);
+                // only feeding the monitored port itself can trigger the handler again
+                let own = self.monitors.get(&(elf_id, *port));
+                if let Some(mon) = monitor.filter(|mon| own.is_some_and(|own| own.1 == *mon)) {
+                    self.monitor_sent.insert(mon);
+                }
+                None
+            }
+            SantaCode::Wait(0) => None, // nothing to wait for, santa goes on
+            SantaCode::Wait(steps) => {
+                // the line keeps how many steps are left, 0 when not waiting
+                let left = match self.santa_result[ip] {
+                    0 => *steps,
+                    left => left,
                 };
+                match left > 1 {
+                    true => {
+                        self.santa_result[ip] = left - 1;
+                        next_ip = ip;
+                    }
+                    false => self.santa_result[ip] = 0,
+                }
+                Some(Event::Tick)
+            }
+            SantaCode::SkipIf { a, cmp, b, len } => {
+                let (a, b) = (self.santa_result[*a] as Int, self.santa_result[*b] as Int);
+                if cmp.invoke(a, b) {
+                    next_ip = ip + len + 1;
+                }
+                None
+            }
+            SantaCode::Repeat { count, block_len } => {
+                // the line keeps how many rounds are left
+                let count = self.santa_result[*count] as Int;
+                match count > 0 {
+                    true => self.santa_result[ip] = count as usize,
+                    false => next_ip = ip + block_len + 1,
+                }
+                None
+            }
+            SantaCode::EndRepeat(start) => {
+                self.santa_result[*start] -= 1;
+                if self.santa_result[*start] > 0 {
+                    next_ip = start + 1;
+                }
+                None
+            }
+            SantaCode::Deliver(line) => {
+                let c = to_char(self.santa_result[*line] as Int);
+                self.deliver(c.encode_utf8(&mut [0; 4]))?;
+                None
+            }
+            SantaCode::DeliverStr(text) => {
+                self.deliver(text)?;
                 None
             }
         };
 
         let trace_code = code.clone();
         let result = self.santa_result[ip];
+        if let Some(tracer) = &mut self.tracer {
+            (tracer.handler)(TraceEvent {
+                elf: None,
+                ip,
+                code: TraceCode::Santa(trace_code.clone()),
+                top: Some(result as Int),
+            });
+        }
         let trace = trace.reset(move || {
             log::trace!(
                 "santa: {ip:4} | {:18} -> {result}",
@@ -415,24 +1376,41 @@ impl<'u> Runtime<'u> {
     fn step_elf(&mut self, id: ElfId) -> Result<Option<Event>, ECode> {
         use Instr::*;
         let unit = self.unit;
+        let cell_bits = self.cell_bits;
         let Some(elf) = self.elves.get_mut(&id) else {
             todo!("no elf {id}");
         };
 
         let code_opt = unit.rooms[elf.room].elf_program.get(elf.ip);
         let code = code_opt.cloned().unwrap_or(Hammock);
+        if code_opt.is_some() {
+            self.coverage.entry(elf.room).or_default().insert(elf.ip);
+        }
 
         let mut event = None;
         let mut next_ip = elf.ip + 1;
         let _g = &elf.ip; // you should write to next_instr instead
 
+        let reach = stack_reach(&code);
+        elf.low_water = elf.low_water.min(elf.stack.len().saturating_sub(reach));
+
         match code {
             Nop | Label(_) => {}
-            Push(value) => elf.stack.push(value),
+            Push(value) => elf.stack.push(wrap_cell(value, cell_bits)),
             Dup(i) => elf.stack.push(elf.top_val(i)?),
+            DupBlock(n) => {
+                let start = match n {
+                    0 => elf.stack.len(),
+                    _ => elf.top_idx(n - 1)?,
+                };
+                elf.stack.extend_from_within(start..);
+            }
             Erase(i) => {
                 elf.stack.remove(elf.top_idx(i)?);
             }
+            Drop => {
+                elf.stack.pop().ok_or(ECode::InvalidIndex(0))?;
+            }
             Tuck(i) => {
                 let index = elf.top_idx(i)?;
                 let top = elf.stack.pop().unwrap();
@@ -443,6 +1421,26 @@ impl<'u> Runtime<'u> {
                 let index = elf.top_idx(i)?;
                 elf.stack.swap(top_i, index);
             }
+            Rot(i) => {
+                let value = elf.stack.remove(elf.top_idx(i)?);
+                elf.stack.push(value);
+            }
+            RotDyn => {
+                let k = elf.top_val(0)?;
+                elf.stack.pop();
+                if !elf.stack.is_empty() {
+                    let k = k.rem_euclid(elf.stack.len() as Int);
+                    elf.stack.rotate_left(k as usize);
+                }
+            }
+            Pick => {
+                let n = elf.top_val(0)?;
+                let n = usize::try_from(n).map_err(|_| ECode::NegativeIndex(n))?;
+                // `n` counts from below itself, check it before popping
+                let picked = elf.top_val(n + 1).map_err(|_| ECode::InvalidIndex(n))?;
+                elf.stack.pop();
+                elf.stack.push(picked);
+            }
             Jmp(_) | IfPos(_) | IfNz(_) => return Err(ECode::InvalidInstr),
             JmpPtr(target) => next_ip = target,
             IfPosPtr(target) => {
@@ -466,76 +1464,360 @@ impl<'u> Runtime<'u> {
                 let result = op.invoke(elf.top_val(1)?, elf.top_val(0)?)?;
                 elf.stack.pop();
                 elf.stack.pop();
-                elf.stack.push(result);
+                elf.stack.push(wrap_cell(result, cell_bits));
             }
             ArithC(op, c) => {
                 let result = op.invoke(elf.top_val(0)?, c)?;
                 elf.stack.pop();
-                elf.stack.push(result);
+                elf.stack.push(wrap_cell(result, cell_bits));
             }
-            In(port) => match elf.inputs.get_mut(&port).map(|p| p.try_read()) {
-                Some(Ok(value)) => elf.stack.push(value),
-                Some(Err(InputError::Empty)) => {
-                    next_ip = elf.ip; // wait here for input
-                    event = Some(Event::Yield);
-                }
-                None | Some(Err(InputError::Closed)) => {
-                    elf.finished = true;
+            Neg => {
+                let result = Op::Sub.invoke(0, elf.top_val(0)?)?;
+                elf.stack.pop();
+                elf.stack.push(wrap_cell(result, cell_bits));
+            }
+            DivMod => {
+                let (a, b) = (elf.top_val(1)?, elf.top_val(0)?);
+                let (div, rem) = (Op::Div.invoke(a, b)?, Op::Mod.invoke(a, b)?);
+                elf.stack.pop();
+                elf.stack.pop();
+                elf.stack.push(wrap_cell(div, cell_bits));
+                elf.stack.push(wrap_cell(rem, cell_bits));
+            }
+            Cmp(cmp) => {
+                let result = cmp.invoke(elf.top_val(1)?, elf.top_val(0)?);
+                elf.stack.pop();
+                elf.stack.pop();
+                elf.stack.push(result as Int);
+            }
+            In(port) => {
+                let read = match &mut self.replay {
+                    Some(replay) => {
+                        let recorded = replay.get_mut(&(id, port)).and_then(VecDeque::pop_front);
+                        Some(recorded.ok_or(InputError::Closed))
+                    }
+                    None => elf.inputs.get_mut(&port).map(|p| p.try_read()),
+                };
+                match read {
+                    Some(Ok(value)) => {
+                        if let Some(log) = &mut self.input_log {
+                            log.push((id, port, value));
+                        }
+                        elf.stack.push(value)
+                    }
+                    Some(Err(InputError::Empty)) => {
+                        next_ip = elf.ip; // wait here for input
+                        event = Some(Event::Yield);
+                    }
+                    None | Some(Err(InputError::Closed)) => {
+                        elf.finished = true;
+                    }
                 }
-            },
+            }
+            // while replaying, the log is all the input there is, so it
+            // never has to wait and is closed once used up
+            Peek(port) => {
+                let next = match &self.replay {
+                    Some(replay) => replay.get(&(id, port)).and_then(|r| r.front().copied()),
+                    None => elf.inputs.get_mut(&port).and_then(|p| p.peek().copied()),
+                };
+                elf.stack.push(next.unwrap_or(NO_INPUT));
+            }
+            Poll(port) => {
+                let state = match (&self.replay, elf.inputs.get_mut(&port)) {
+                    (Some(replay), _) => match replay.get(&(id, port)) {
+                        Some(recorded) if !recorded.is_empty() => 1,
+                        _ => -1,
+                    },
+                    (None, Some(input)) => match (input.has_pending(), input.is_closed()) {
+                        (true, _) => 1,
+                        (false, false) => 0,
+                        (false, true) => -1,
+                    },
+                    (None, None) => -1,
+                };
+                elf.stack.push(state);
+            }
+            InLen(port) => {
+                let waiting = match &self.replay {
+                    Some(replay) => replay.get(&(id, port)).map_or(0, VecDeque::len),
+                    None => elf.inputs.get_mut(&port).map_or(0, |p| p.buffered_len()),
+                };
+                elf.stack.push(waiting as Int);
+            }
             Out(port) => {
                 let top = elf.top_val(0)?;
-                elf.stack.pop();
-                if let Some(output) = elf.outputs.get(&port) {
-                    output.write(top);
-                    event = Some(Event::Write(port));
-                } else {
-                    log::warn!("Elf {:?} writes to unused port {port:?}", elf.name);
+                match elf.outputs.get(&port).map(|output| output.try_write(top)) {
+                    Some(Ok(())) => {
+                        elf.stack.pop();
+                        event = Some(Event::Write(port));
+                    }
+                    Some(Err(OutputError::Full)) => {
+                        next_ip = elf.ip; // wait here until the reader catches up
+                        event = Some(Event::Yield);
+                    }
+                    None => {
+                        elf.stack.pop();
+                        log::warn!("Elf {:?} writes to unused port {port:?}", elf.name);
+                    }
                 }
             }
+            Feed(port) => {
+                let top = elf.top_val(0)?;
+                // no pipe feeds the port yet, it gets a closed one like `ENV` inputs
+                let input = elf
+                    .inputs
+                    .entry(port)
+                    .or_insert_with(|| InputPipe::new_connected(&mut OutputPipe::new()));
+                input.write_direct(top);
+                elf.stack.pop();
+            }
             Read(slot) => {
-                elf.stack.push(elf.sleeve[slot as usize]);
+                let value = elf
+                    .sleeve
+                    .get(slot as usize)
+                    .ok_or(ECode::InvalidSlot(slot))?;
+                elf.stack.push(*value);
             }
             Write(slot) => {
-                elf.sleeve[slot as usize] = elf.top_val(0)?;
+                let top = elf.top_val(0)?;
+                let cell = elf
+                    .sleeve
+                    .get_mut(slot as usize)
+                    .ok_or(ECode::InvalidSlot(slot))?;
+                *cell = top;
                 elf.stack.pop();
             }
+            DumpSleeve => elf.stack.extend_from_slice(&elf.sleeve[..]),
+            LoadSleeve => {
+                let start = elf.top_idx(elf.sleeve.len() - 1)?;
+                elf.sleeve.copy_from_slice(&elf.stack[start..]);
+                elf.stack.truncate(start);
+            }
             StackLen => {
                 elf.stack.push(elf.stack.len() as Int);
             }
+            Rand => {
+                let bound = elf.top_val(0)?;
+                if bound <= 0 {
+                    return Err(ECode::InvalidBound(bound));
+                }
+                elf.stack.pop();
+                elf.stack.push((self.rng.next() % bound as u64) as Int);
+            }
+            Tick => {
+                elf.stack.push(self.ticks);
+                self.ticks += 1;
+            }
+            Clear => elf.stack.clear(),
             Hammock => {
                 elf.finished = true;
             }
+            Break => event = Some(Event::Breakpoint),
+            Probe(name) => log::debug!("probe {name}: elf {} {:?}", elf.name, elf.stack),
+            Extension(opcode) => match &mut self.extension {
+                Some(ext) => {
+                    let mut view = ElfView {
+                        name: &elf.name,
+                        stack: &mut elf.stack,
+                        sleeve: &mut elf.sleeve,
+                    };
+                    (ext.handler)(&mut view, opcode)?
+                }
+                None => return Err(ECode::InvalidInstr),
+            },
         };
 
         if elf.finished {
+            elf.warn_leftovers();
             event = Some(Event::Dequeue);
         }
 
-        log::trace!(
-            "elf {} > {:>3} | {:<25}{:?}",
-            elf.name,
-            elf.ip,
-            format!("{:?}", code),
-            &elf.stack[elf.stack.len().saturating_sub(10)..]
-        );
+        let category = code.category();
+        if category.mask() & self.trace_mask != 0 {
+            log::trace!(
+                "elf {} > {:>3} | {:<8}{:<25}{:?}",
+                elf.name,
+                elf.ip,
+                format!("{category:?}"),
+                format!("{:?}", code),
+                &elf.stack[elf.stack.len().saturating_sub(10)..]
+            );
+            if let Some(tracer) = &mut self.tracer {
+                (tracer.handler)(TraceEvent {
+                    elf: Some(elf.id),
+                    ip: elf.ip,
+                    code: TraceCode::Elf(code),
+                    top: elf.stack.last().copied(),
+                });
+            }
+        }
+
+        match event {
+            Some(Event::Yield) => elf.turns.1 += 1,
+            _ => elf.turns.0 += 1,
+        }
+
+        if let Some(trace) = &mut self.chrome_trace
+            && let Err(e) = trace.event(elf, &code)
+        {
+            log::warn!("Stopped the chrome trace: {e}");
+            self.chrome_trace = None;
+        }
 
         _ = _g;
         elf.ip = next_ip;
         Ok(event)
     }
 
+    /// Write to elf's input through santa's own pipe
+    fn send(&mut self, elf_id: ElfId, port: Port, value: Int) {
+        let Some(elf) = self.elves.get_mut(&elf_id) else {
+            log::warn!("Santa sends {value} to elf {elf_id}, but it is already asleep");
+            return;
+        };
+        let output = self.santa_outputs.entry((elf_id, port)).or_insert_with(|| {
+            let mut output = OutputPipe::new();
+            elf.ensure_input(port, &mut output);
+            output
+        });
+        output.write(value);
+    }
+
+    /// Read the next chunk of every input file whose elf (or the given elf) has read
+    /// everything so far, so only one chunk per file is in memory. Files are closed at
+    /// their end. Returns whether anything changed.
+    fn feed_in_files(&mut self, only: Option<ElfId>) -> Result<bool, ECode> {
+        let mut fed = false;
+        let mut i = 0;
+        while i < self.in_files.len() {
+            let in_file = &mut self.in_files[i];
+            let (elf_id, port) = in_file.dst;
+            let waiting = match self.elves.get_mut(&elf_id) {
+                _ if only.is_some_and(|id| id != elf_id) => Some(false),
+                Some(elf) => elf.inputs.get_mut(&port).map(|p| p.buffered_len() == 0),
+                None => None,
+            };
+            let more = match waiting {
+                Some(false) => true,
+                Some(true) => {
+                    fed = true;
+                    (in_file.feed()).map_err(|e| ECode::Io(in_file.file.clone(), e.into()))?
+                }
+                None => false, // the elf is asleep, nobody will read it
+            };
+            match more {
+                true => i += 1,
+                false => {
+                    self.in_files.swap_remove(i);
+                }
+            }
+        }
+        Ok(fed)
+    }
+
+    /// Write `text` to the deliver output
+    fn deliver(&mut self, text: &str) -> Result<(), ECode> {
+        let flush = self.flush_mode == FlushMode::Prompt;
+        let written = match &mut self.output {
+            Out::Std => write_str(&mut io::stdout(), text, flush),
+            Out::Buffer(buf) => {
+                buf.push_str(text);
+                Ok(())
+            }
+            Out::Writer(w) => {
+                let mut w = w.lock().unwrap_or_else(PoisonError::into_inner);
+                write_str(&mut *w, text, flush)
+            }
+        };
+        written.map_err(|e| ECode::Io("output".into(), e.into()))
+    }
+
+    /// Pass the values waiting in queues on to their readers, in turns.
+    /// With `close`, readers of queues without writers are hung up on.
+    /// Returns whether anything changed.
+    fn pump_queues(&mut self, close: bool) -> bool {
+        let mut pumped = false;
+        for queue in self.queues.values_mut() {
+            while let (Some(&value), false) = (queue.input.peek(), queue.readers.is_empty()) {
+                let reader = &queue.readers[queue.next_reader % queue.readers.len()];
+                if reader.try_write(value).is_err() {
+                    break; // the reader whose turn it is has no room
+                }
+                _ = queue.input.try_read();
+                queue.next_reader += 1;
+                pumped = true;
+            }
+            let drained = queue.input.is_closed() && !queue.input.has_pending();
+            if close && drained && !queue.readers.is_empty() {
+                queue.readers.clear();
+                pumped = true;
+            }
+        }
+        pumped
+    }
+
     fn flush_outs(&mut self) {
         for f in self.out_files.iter_mut() {
             while let Ok(v) = f.pipe.try_read() {
-                let c = v as u8 as char; // TODO: better encoding
+                let c = to_char(v);
                 write!(&mut f.writer, "{c}").unwrap();
             }
+            f.writer.flush().unwrap();
+        }
+    }
+}
+
+/// Run `unit` to the end and return what santa delivered.
+///
+/// Meant for tests, panics when the program fails or does not finish within `max_steps`.
+pub fn assert_terminates(unit: &Unit, max_steps: u64) -> String {
+    let mut rt = Runtime::new(unit);
+    rt.set_output(Out::Buffer(String::new()));
+
+    match rt.run(RunCommand::RunWithLimit(max_steps)) {
+        Ok(RunOk::LimitReached { steps }) => panic!(
+            "program did not terminate within {steps} steps\nschedule: {:?}\nmonitors: {:?}\nelves: {:#?}",
+            rt.schedule, rt.monitors, rt.elves
+        ),
+        Ok(_) => {}
+        Err(e) => panic!("program failed: {e}"),
+    }
+    rt.take_output().unwrap()
+}
+
+impl Queue {
+    fn new() -> Self {
+        Self {
+            input: InputPipe::new_closed(),
+            readers: Vec::new(),
+            next_reader: 0,
         }
     }
 }
 
 impl Elf {
+    pub fn id(&self) -> ElfId {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn room(&self) -> RoomId {
+        self.room
+    }
+    /// Line of the next instruction in the room's program
+    pub fn ip(&self) -> ElfLine {
+        self.ip
+    }
+    /// Stack from bottom to top
+    pub fn stack(&self) -> &[Int] {
+        &self.stack
+    }
+    pub fn sleeve(&self) -> &[Int] {
+        &self.sleeve[..]
+    }
+
     fn ensure_output(&mut self, port: Port) -> &mut OutputPipe<i64> {
         self.outputs
             .entry(port)
@@ -558,32 +1840,176 @@ impl Elf {
     pub fn top_val(&self, from_top: usize) -> Result<Int, ECode> {
         Ok(self.stack[self.top_idx(from_top)?])
     }
+
+    /// Lint the stack of a sleeping elf, leftovers are sometimes the result, sometimes a bug
+    fn warn_leftovers(&self) {
+        let untouched = self.low_water.min(self.init_len);
+        if untouched > 0 {
+            log::warn!(
+                "Elf {:?} falls asleep without touching its initial stack {:?}",
+                self.name,
+                &self.stack[..untouched]
+            );
+        }
+        if self.stack.len() > LEFTOVER_LIMIT {
+            log::warn!(
+                "Elf {:?} falls asleep with {} sheets on the stack",
+                self.name,
+                self.stack.len()
+            );
+        }
+    }
+}
+
+/// Keep the lowest `bits` of `value` and sign-extend them
+fn wrap_cell(value: Int, bits: u32) -> Int {
+    match bits {
+        64.. => value,
+        _ => {
+            let shift = Int::BITS - bits;
+            (value << shift) >> shift
+        }
+    }
+}
+
+/// xorshift64*, good enough for puzzles and the same on every platform
+#[derive(Debug, Clone)]
+struct XorShift(u64);
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // splitmix64, so that close seeds start far apart
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        // the state must not be zero
+        Self(if z == 0 { 0x9E37_79B9_7F4A_7C15 } else { z })
+    }
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+/// Unicode character with code point `value`, the replacement character for invalid ones
+fn to_char(value: Int) -> char {
+    let c = u32::try_from(value).ok().and_then(char::from_u32);
+    c.unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+fn write_str(w: &mut (impl io::Write + ?Sized), text: &str, flush: bool) -> io::Result<()> {
+    w.write_all(text.as_bytes())?;
+    if flush {
+        w.flush()?;
+    }
+    Ok(())
+}
+
+/// `text` as a quoted JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// How many sheets from the top an instruction reads or pops
+fn stack_reach(code: &Instr) -> usize {
+    use Instr::*;
+    match code {
+        Dup(i) | Erase(i) | Tuck(i) | Swap(i) | Rot(i) => i + 1,
+        DupBlock(n) => *n,
+        LoadSleeve => 10,
+        Arith(_) | Cmp(_) | DivMod => 2,
+        ArithC(..) | Neg | Drop | Write(_) | Out(_) | Feed(_) | IfPosPtr(_) | IfNzPtr(_)
+        | IfPos(_) | IfNz(_) | Rand => 1,
+        RotDyn | Pick | Extension(_) => usize::MAX,
+        _ => 0,
+    }
 }
 
 impl Op {
     fn invoke(&self, a: i64, b: i64) -> Result<Int, ECode> {
+        let checked = |result: Option<Int>| result.ok_or(ECode::Overflow(*self, a, b));
         return Ok(match self {
-            Op::Add => a + b,
-            Op::Sub => a - b,
-            Op::Mul => a * b,
+            Op::Add => checked(a.checked_add(b))?,
+            Op::Sub => checked(a.checked_sub(b))?,
+            Op::Mul => checked(a.checked_mul(b))?,
             Op::Div if b == 0 => return Err(ECode::DivisionByZero),
-            Op::Div => a / b,
-            Op::Mod => a % b,
+            Op::Div => checked(a.checked_div(b))?,
+            Op::Mod if b == 0 => return Err(ECode::DivisionByZero),
+            Op::Mod => checked(a.checked_rem(b))?,
+            Op::And => a & b,
+            Op::Or => a | b,
+            Op::Xor => a ^ b,
+            Op::Shl | Op::Shr if !(0..Int::BITS as Int).contains(&b) => {
+                return Err(ECode::InvalidShift(b));
+            }
+            Op::Shl => a << b,
+            Op::Shr => a >> b,
         });
     }
 }
 
+impl Cmp {
+    fn invoke(&self, a: Int, b: Int) -> bool {
+        match self {
+            Cmp::Eq => a == b,
+            Cmp::Ne => a != b,
+            Cmp::Lt => a < b,
+            Cmp::Gt => a > b,
+            Cmp::Le => a <= b,
+            Cmp::Ge => a >= b,
+        }
+    }
+}
+
 impl<'u> fmt::Display for Error<'u> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Elf encountered a problem and doesn't know what to do: ")?;
-        match self.code {
+        match &self.code {
             ECode::InvalidIndex(i) => writeln!(f, "invalid index {i}"),
+            ECode::NegativeIndex(i) => writeln!(f, "invalid index {i}"),
+            ECode::InvalidSlot(slot) => writeln!(f, "invalid sleeve slot {slot}"),
             ECode::InvalidInstr => writeln!(f, "invalid instruction"),
             ECode::DivisionByZero => writeln!(f, "division by zero"),
+            ECode::Overflow(op, a, b) => writeln!(f, "integer overflow in {a} {} {b}", op.symbol()),
+            ECode::InvalidShift(b) => writeln!(f, "invalid shift by {b}"),
+            ECode::InvalidBound(n) => writeln!(f, "random number below {n}, which is not positive"),
+            ECode::Io(file, e) => writeln!(f, "{e}: {file}"),
+            ECode::Env(var) => writeln!(f, "environment variable {var} is not set or not a number"),
+            ECode::Deadlock(stuck) => {
+                write!(f, "deadlock, everyone is waiting:")?;
+                for (who, port) in stuck {
+                    write!(f, " {who} on port {port},")?;
+                }
+                writeln!(f)
+            }
+            ECode::MonitorReentrancy => writeln!(f, "monitor keeps triggering itself"),
+            ECode::NoSuchMonitor { elf, port } => {
+                writeln!(f, "receive from port {port} of elf {elf}, not monitored")
+            }
+            ECode::ElfGone(elf) => writeln!(f, "connect to elf {elf}, which is already asleep"),
+            ECode::DuplicateMonitor { elf, port } => {
+                writeln!(f, "port {port} of elf {elf} is already monitored")
+            }
         }?;
 
-        if let Some(room) = self.room.map(|i| &self.unit.rooms[i]) {
-            let (x, y) = room.ip_to_tile[&self.ip].clone();
+        if let Some(room) = self.room.map(|i| &self.unit.rooms[i])
+            && let Some((x, y)) = room.ip_to_tile.get(&self.ip)
+        {
             write!(f, "  pos=({x},{y})")?;
         }
         writeln!(f, "  stack: {:?}", self.stack)?;
@@ -591,3 +2017,1822 @@ impl<'u> fmt::Display for Error<'u> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::translate::{TranslationInput, translate};
+
+    fn make_unit(src: &str) -> Unit {
+        let result = translate(vec![TranslationInput::Buffer {
+            name: None,
+            text: src.into(),
+        }]);
+        match result {
+            Ok(unit) => unit,
+            Err(errors) => {
+                errors.iter().for_each(|e| println!("{e}"));
+                panic!("translation failed");
+            }
+        }
+    }
+
+    fn single_elf(program: Vec<Instr>) -> Unit {
+        Unit {
+            cell_bits: 64,
+            rooms: vec![Room::new_testing(program)],
+            santa: vec![SantaCode::SetupElf {
+                name: None,
+                room: 0,
+                entry: 0,
+                init_stack: vec![],
+            }],
+        }
+    }
+
+    /// Runs a straight-line `program` and returns the stack right before the elf falls asleep
+    fn final_stack(program: Vec<Instr>) -> Vec<Int> {
+        let steps = program.len() + 2; // santa sets up the elf and leaves
+        let unit = single_elf(program);
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::Step(steps)).unwrap();
+        rt.elves[&0].stack.clone()
+    }
+
+    fn output(rt: &Runtime) -> String {
+        match &rt.output {
+            Out::Buffer(buf) => buf.clone(),
+            Out::Std | Out::Writer(_) => panic!("output is not buffered"),
+        }
+    }
+
+    const PIPELINE: &str = "
+        workshop emit:
+            floorplan:
+                mv O1 m<
+                m> e> ?s
+                      Hm
+            ;
+        ;
+
+        workshop inc:
+            floorplan:
+                m> I1 +1 O1 mv
+                m^ e<       m<
+            ;
+        ;
+
+        Santa will:
+            setup emit for elf Producer (65 66 67)
+            setup inc for elf Consumer ()
+            setup Producer.1 -> Consumer.1
+
+            monitor Consumer.1:
+                receive x
+                deliver x
+            ;
+        ;
+    ";
+
+    #[test]
+    fn greedy_schedule_runs_elf_to_completion() {
+        let unit = make_unit(PIPELINE);
+
+        let mut outputs = Vec::new();
+        for policy in [SchedulePolicy::RoundRobin, SchedulePolicy::SequentialGreedy] {
+            let mut rt = Runtime::new(&unit);
+            rt.policy = policy;
+            rt.output = Out::Buffer(String::new());
+
+            // step until the producer is set up and falls asleep
+            while !rt.elves.contains_key(&0) {
+                rt.run(RunCommand::Step(1)).unwrap();
+            }
+            while rt.elves.contains_key(&0) {
+                rt.run(RunCommand::Step(1)).unwrap();
+            }
+            outputs.push(output(&rt));
+
+            rt.run(RunCommand::RunToEnd).unwrap();
+            assert_eq!(output(&rt), "DCB");
+        }
+
+        // round robin lets the consumer interleave, greedy does not
+        assert_ne!(outputs[0], "");
+        assert_eq!(outputs[1], "");
+    }
+
+    #[test]
+    fn modulo_by_zero() {
+        let unit = single_elf(vec![Instr::Push(5), Instr::ArithC(Op::Mod, 0)]);
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::DivisionByZero));
+        assert_eq!(error.stack, [5]);
+    }
+
+    #[test]
+    fn div_mod() {
+        use Instr::*;
+        assert_eq!(final_stack(vec![Push(17), Push(5), DivMod]), [3, 2]);
+        // rounds towards zero, like `/_` and `%_`
+        assert_eq!(final_stack(vec![Push(-17), Push(5), DivMod]), [-3, -2]);
+        assert_eq!(final_stack(vec![Push(17), Push(-5), DivMod]), [-3, 2]);
+
+        let unit = single_elf(vec![Push(5), Push(0), DivMod]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::DivisionByZero));
+        assert_eq!(error.stack, [5, 0]);
+    }
+
+    #[test]
+    fn open_missing_file() {
+        let unit = make_unit(
+            "
+            workshop echo:
+                floorplan:
+                    e> I1 Hm
+                ;
+            ;
+
+            Santa will:
+                setup echo for elf Bobo ()
+                setup FILE(\"does/not/exist.txt\") -> Bobo.1
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::Io(..)));
+        assert!(error.to_string().contains("does/not/exist.txt"));
+    }
+
+    #[test]
+    fn rotate_dynamic() {
+        use Instr::*;
+        let stack = [Push(1), Push(2), Push(3), Push(4)];
+
+        let rotate = |k| final_stack([&stack[..], &[Push(k), RotDyn]].concat());
+        assert_eq!(rotate(1), [2, 3, 4, 1]);
+        assert_eq!(rotate(0), [1, 2, 3, 4]);
+        assert_eq!(rotate(6), [3, 4, 1, 2]);
+        assert_eq!(rotate(-1), [4, 1, 2, 3]);
+
+        assert_eq!(final_stack(vec![Push(3), RotDyn]), []);
+    }
+
+    #[test]
+    fn rand_is_seeded() {
+        use Instr::*;
+        let program: Vec<_> = (0..8).flat_map(|_| [Push(100), Rand]).collect();
+        let steps = program.len() + 2; // santa sets up the elf and leaves
+        let unit = single_elf(program);
+        let numbers = |seed| {
+            let mut rt = Runtime::new_seeded(&unit, seed);
+            rt.run(RunCommand::Step(steps)).unwrap();
+            rt.elves[&0].stack.clone()
+        };
+
+        let first = numbers(7);
+        assert_eq!(first, numbers(7));
+        for seed in 0..8 {
+            assert_ne!(numbers(seed), numbers(seed + 1), "seed {seed}");
+        }
+        assert!(first.iter().all(|n| (0..100).contains(n)), "{first:?}");
+
+        let mut rt = Runtime::new_seeded(&unit, 7);
+        rt.run(RunCommand::Step(5)).unwrap();
+        rt.reset();
+        rt.run(RunCommand::Step(steps)).unwrap();
+        assert_eq!(rt.elves[&0].stack, first);
+
+        let unit = single_elf(vec![Push(0), Rand]);
+        let error = Runtime::new(&unit).run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidBound(0)), "{error}");
+    }
+
+    #[test]
+    fn tick_is_unique() {
+        let unit = make_unit(
+            "
+            workshop count:
+                floorplan:
+                    e> !t !t !t Hm
+                ;
+            ;
+            Santa will:
+                setup count for elf A ()
+                setup count for elf B ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.quantum = Some(1);
+        rt.run(RunCommand::Step(3)).unwrap(); // santa sets up the elves and leaves
+        rt.run(RunCommand::Step(6)).unwrap();
+
+        assert_eq!(rt.elves[&0].stack, [0, 2, 4]);
+        assert_eq!(rt.elves[&1].stack, [1, 3, 5]);
+    }
+
+    #[test]
+    fn pick() {
+        use Instr::*;
+        let stack = [Push(1), Push(2), Push(3), Push(4)];
+
+        let pick = |n| final_stack([&stack[..], &[Push(n), Pick]].concat());
+        assert_eq!(pick(2), [1, 2, 3, 4, 2]);
+        assert_eq!(pick(0), [1, 2, 3, 4, 4]);
+
+        let error = |n| {
+            let unit = single_elf([&stack[..], &[Push(n), Pick]].concat());
+            let error = Runtime::new(&unit).run(RunCommand::RunToEnd).unwrap_err();
+            (error.code.clone(), error.to_string(), error.stack.clone())
+        };
+        let (code, message, stack) = error(4);
+        assert!(matches!(code, ECode::InvalidIndex(4)), "{message}");
+        assert_eq!(stack, [1, 2, 3, 4, 4]);
+
+        let (code, message, stack) = error(-2);
+        assert!(matches!(code, ECode::NegativeIndex(-2)), "{message}");
+        assert!(message.contains("invalid index -2"), "{message}");
+        assert_eq!(stack, [1, 2, 3, 4, -2]);
+    }
+
+    #[test]
+    fn detect_deadlock() {
+        let unit = make_unit(
+            "
+            workshop echo:
+                floorplan:
+                    e> I1 O1 Hm
+                ;
+            ;
+
+            Santa will:
+                setup echo for elf Alice ()
+                setup echo for elf Bob ()
+                setup Alice.1 -> Bob.1
+                setup Bob.1 -> Alice.1
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        match &error.code {
+            ECode::Deadlock(stuck) => {
+                let mut names: Vec<_> = stuck
+                    .iter()
+                    .map(|(who, port)| (who.as_str(), *port))
+                    .collect();
+                names.sort();
+                assert_eq!(names, [("Alice", 1), ("Bob", 1)]);
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn step_limit() {
+        let unit = single_elf(vec![Instr::Push(1), Instr::JmpPtr(0)]);
+        let mut rt = Runtime::new(&unit);
+
+        let result = rt.run(RunCommand::RunWithLimit(100)).unwrap();
+        assert!(matches!(result, RunOk::LimitReached { steps: 100 }));
+
+        // the runtime is left as is and can be resumed
+        let stack_len = rt.elves[&0].stack.len();
+        rt.run(RunCommand::RunWithLimit(10)).unwrap();
+        assert_eq!(rt.elves[&0].stack.len(), stack_len + 5);
+    }
+
+    #[test]
+    fn breakpoint_and_continue() {
+        let unit = single_elf(vec![Instr::Push(1), Instr::Break, Instr::Push(2)]);
+        let mut rt = Runtime::new(&unit);
+
+        let result = rt.run(RunCommand::Continue).unwrap();
+        assert!(matches!(result, RunOk::Breakpoint));
+        assert_eq!(rt.elves[&0].stack, vec![1]);
+
+        // resumes after the breakpoint instead of hitting it again
+        let result = rt.run(RunCommand::Continue).unwrap();
+        assert!(matches!(result, RunOk::Done));
+        assert!(rt.elves.is_empty());
+    }
+
+    #[test]
+    fn extension_handler() {
+        let unit = single_elf(vec![Instr::Push(21), Instr::Extension(7)]);
+        let mut rt = Runtime::new(&unit);
+        rt.set_extension_handler(Box::new(|elf, opcode| {
+            assert_eq!(opcode, 7);
+            let top = elf.pop()?;
+            elf.push(top * 2);
+            Ok(())
+        }));
+
+        rt.run(RunCommand::Step(4)).unwrap();
+        assert_eq!(rt.elves[&0].stack, vec![42]);
+    }
+
+    #[test]
+    fn extension_without_handler() {
+        let unit = single_elf(vec![Instr::Extension(7)]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidInstr));
+    }
+
+    #[test]
+    fn inspect_elf() {
+        let unit = single_elf(vec![Instr::Push(7), Instr::Push(8)]);
+        let mut rt = Runtime::new(&unit);
+        assert!(rt.elf(0).is_none());
+
+        rt.run(RunCommand::Step(2)).unwrap(); // santa sets up the elf and leaves
+        let elf = rt.elf(0).unwrap();
+        assert_eq!((elf.room(), elf.ip()), (0, 0));
+        assert!(elf.stack().is_empty());
+
+        rt.run(RunCommand::Step(1)).unwrap();
+        let elf = rt.elf(0).unwrap();
+        assert_eq!(elf.ip(), 1);
+        assert_eq!(elf.stack(), [7]);
+        assert_eq!(elf.name(), ELF_NAMES[0]);
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let unit = make_unit(
+            "
+            workshop idle:
+                floorplan:
+                    e> 01 Hm
+                ;
+            ;
+            Santa will:
+                setup idle for elf Buddy ()
+                setup idle for elf Buddy ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.preset_input("Buddy", 1, 7);
+
+        rt.run(RunCommand::Step(2)).unwrap();
+        assert_eq!(rt.elf(0).unwrap().name(), "Buddy");
+        assert_eq!(rt.elf(1).unwrap().name(), "Buddy#2");
+        // both are still Buddy to presets
+        let second = rt.elves.get_mut(&1).unwrap();
+        assert_eq!(second.inputs.get_mut(&1).unwrap().buffered_len(), 1);
+
+        let (ok, logs) = crate::logger::capture(|| rt.run(RunCommand::RunToEnd).is_ok());
+        assert!(ok);
+        assert!(
+            logs.iter().any(|l| l.starts_with("elf Buddy#2 >")),
+            "{logs:#?}"
+        );
+    }
+
+    #[test]
+    fn name_provider() {
+        let unit = make_unit(
+            "
+            workshop idle:
+                floorplan:
+                    e> Hm
+                ;
+            ;
+            Santa will:
+                setup idle for elf ()
+                setup idle for elf Named ()
+                setup idle for elf ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.set_name_provider(Box::new(|id| format!("worker-{id}")));
+        rt.run(RunCommand::Step(4)).unwrap(); // santa sets up the elves and leaves
+
+        let names: Vec<_> = (0..3).map(|id| rt.elf(id).unwrap().name()).collect();
+        assert_eq!(names, ["worker-0", "Named", "worker-2"]);
+    }
+
+    #[test]
+    fn error_position() {
+        let unit = make_unit(
+            "
+            workshop divide:
+                floorplan:
+                    e> 05 /0 Hm
+                ;
+            ;
+
+            Santa will:
+                setup divide for elf Dora ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::DivisionByZero));
+        assert!(error.to_string().contains("pos=(2,0)"), "{error}");
+    }
+
+    #[test]
+    fn error_without_positions() {
+        let unit = single_elf(vec![Instr::Push(5), Instr::ArithC(Op::Div, 0)]);
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(!error.to_string().contains("pos="), "{error}");
+    }
+
+    #[test]
+    fn invalid_sleeve_slot() {
+        let unit = single_elf(vec![Instr::Push(5), Instr::Write(200)]);
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidSlot(200)));
+        assert!(
+            error.to_string().contains("invalid sleeve slot 200"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn dump_load_sleeve() {
+        use Instr::*;
+        let mut program: Vec<_> = (0..10).map(|v| Push(v * 11)).collect();
+        program.extend([LoadSleeve, Push(-1), Write(3), DumpSleeve]);
+        let expected = [0, 11, 22, -1, 44, 55, 66, 77, 88, 99];
+        assert_eq!(final_stack(program.clone()), expected);
+
+        program.extend([LoadSleeve, Read(9), Read(0)]);
+        assert_eq!(final_stack(program), [99, 0]);
+
+        let unit = single_elf(vec![Push(1), Push(2), LoadSleeve]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidIndex(9)), "{error}");
+        assert_eq!(error.stack, [1, 2]);
+    }
+
+    const ECHO: &str = "
+        workshop echo:
+            floorplan:
+                m> e> I1 O1 mv
+                m^          m<
+            ;
+        ;
+    ";
+
+    #[test]
+    fn send_to_elf() {
+        let src = format!(
+            "{ECHO}
+            Santa will:
+                setup echo for elf Echo ()
+                send (72 105) to Echo.1
+                monitor Echo.1:
+                    receive x
+                    deliver x
+                ;
+            ;
+            "
+        );
+        let unit = make_unit(&src);
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(output(&rt), "Hi");
+    }
+
+    #[test]
+    fn monitor_reentrancy() {
+        let src = format!(
+            "{ECHO}
+            Santa will:
+                setup echo for elf Echo ()
+                send 65 to Echo.1
+                monitor Echo.1:
+                    receive x
+                    deliver x
+                    send x
+                ;
+            ;
+            "
+        );
+        let unit = make_unit(&src);
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+        rt.monitor_depth_limit = 5;
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::MonitorReentrancy));
+        assert_eq!(output(&rt), "AAAAA");
+    }
+
+    #[test]
+    fn monitor_forwarding() {
+        // sending to another elf never re-triggers the handler
+        let src = format!(
+            "{ECHO}
+            Santa will:
+                setup echo for elf Echo ()
+                setup echo for elf Other ()
+                send (65 66 67 68 69 70) to Echo.1
+                monitor Echo.1:
+                    receive x
+                    send x to Other.1
+                ;
+                monitor Other.1:
+                    receive y
+                    deliver y
+                ;
+            ;
+            "
+        );
+        let unit = make_unit(&src);
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+        rt.monitor_depth_limit = 2;
+
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(output(&rt), "ABCDEF");
+    }
+
+    #[test]
+    fn compare() {
+        use Instr::Push;
+        let compare = |a, b, cmp| final_stack(vec![Push(7), Push(a), Push(b), Instr::Cmp(cmp)]);
+
+        for (cmp, lt, eq, gt) in [
+            (Cmp::Eq, 0, 1, 0),
+            (Cmp::Ne, 1, 0, 1),
+            (Cmp::Lt, 1, 0, 0),
+            (Cmp::Gt, 0, 0, 1),
+            (Cmp::Le, 1, 1, 0),
+            (Cmp::Ge, 0, 1, 1),
+        ] {
+            assert_eq!(compare(2, 3, cmp), [7, lt], "2 {cmp:?} 3");
+            assert_eq!(compare(3, 3, cmp), [7, eq], "3 {cmp:?} 3");
+            assert_eq!(compare(3, -2, cmp), [7, gt], "3 {cmp:?} -2");
+        }
+    }
+
+    #[test]
+    fn bitwise() {
+        use Instr::*;
+        assert_eq!(
+            final_stack(vec![Push(0b1100), Push(0b1010), Arith(Op::Xor)]),
+            [0b0110]
+        );
+        assert_eq!(
+            final_stack(vec![Push(0b1100), Push(0b1010), Arith(Op::And)]),
+            [0b1000]
+        );
+        assert_eq!(
+            final_stack(vec![Push(0b1100), Push(0b1010), Arith(Op::Or)]),
+            [0b1110]
+        );
+        assert_eq!(final_stack(vec![Push(3), ArithC(Op::Shl, 4)]), [48]);
+        assert_eq!(final_stack(vec![Push(-48), ArithC(Op::Shr, 4)]), [-3]);
+    }
+
+    #[test]
+    fn invalid_shift() {
+        for shift in [-1, 64] {
+            let unit = single_elf(vec![
+                Instr::Push(1),
+                Instr::Push(shift),
+                Instr::Arith(Op::Shl),
+            ]);
+            let mut rt = Runtime::new(&unit);
+
+            let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+            assert!(matches!(error.code, ECode::InvalidShift(b) if b == shift));
+        }
+    }
+
+    #[test]
+    fn preset_input() {
+        let unit = make_unit(
+            "
+            workshop add:
+                floorplan:
+                    e> I1 I2 +_ Hm
+                ;
+            ;
+
+            Santa will:
+                setup add for elf Adder ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.preset_input("Adder", 1, 42);
+        rt.preset_input("Adder", 2, -7);
+        rt.preset_input("Nobody", 1, 1);
+
+        // santa sets up the elf and leaves, then I1 I2 +_
+        rt.run(RunCommand::Step(5)).unwrap();
+        assert_eq!(rt.elf(0).unwrap().stack(), [35]);
+    }
+
+    #[test]
+    fn negate() {
+        use Instr::*;
+        assert_eq!(final_stack(vec![Push(1), Push(5), Neg]), [1, -5]);
+        assert_eq!(final_stack(vec![Push(-5), Neg]), [5]);
+        assert_eq!(final_stack(vec![Push(Int::MAX), Neg]), [-Int::MAX]);
+
+        let unit = single_elf(vec![Push(Int::MIN), Neg]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::Overflow(Op::Sub, 0, Int::MIN)));
+    }
+
+    #[test]
+    fn overflow() {
+        use Instr::*;
+        let error = |a, op, b| {
+            let unit = single_elf(vec![Push(a), Push(b), Arith(op)]);
+            let mut rt = Runtime::new(&unit);
+            rt.run(RunCommand::RunToEnd).unwrap_err().code
+        };
+        let (max, min) = (Int::MAX, Int::MIN);
+        assert_eq!(final_stack(vec![Push(max - 1), ArithC(Op::Add, 1)]), [max]);
+        assert_eq!(final_stack(vec![Push(min + 1), ArithC(Op::Sub, 1)]), [min]);
+
+        for (a, op, b) in [
+            (Int::MAX, Op::Add, 1),
+            (Int::MIN, Op::Sub, 1),
+            (Int::MAX, Op::Mul, 2),
+            (Int::MIN, Op::Div, -1),
+            (Int::MIN, Op::Mod, -1),
+        ] {
+            let code = error(a, op, b);
+            assert!(matches!(code, ECode::Overflow(o, x, y) if (o, x, y) == (op, a, b)));
+        }
+    }
+
+    #[test]
+    fn pipeline_terminates() {
+        assert_eq!(assert_terminates(&make_unit(PIPELINE), 1000), "DCB");
+    }
+
+    #[test]
+    #[should_panic(expected = "did not terminate within 100 steps")]
+    fn endless_loop_does_not_terminate() {
+        let unit = single_elf(vec![Instr::Push(1), Instr::JmpPtr(0)]);
+        assert_terminates(&unit, 100);
+    }
+
+    #[test]
+    fn drop_top() {
+        use Instr::*;
+        assert_eq!(final_stack(vec![Push(1), Push(2), Drop]), [1]);
+
+        let unit = single_elf(vec![Drop]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidIndex(0)));
+    }
+
+    #[test]
+    fn rotate() {
+        use Instr::*;
+        let stack = |n| final_stack(vec![Push(1), Push(2), Push(3), Rot(n)]);
+        assert_eq!(stack(2), [2, 3, 1]);
+        assert_eq!(stack(0), [1, 2, 3]);
+
+        let unit = single_elf(vec![Push(1), Push(2), Rot(2)]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidIndex(2)));
+    }
+
+    #[test]
+    fn dup_block() {
+        use Instr::*;
+        let stack = |n| final_stack(vec![Push(1), Push(2), Push(3), DupBlock(n)]);
+        assert_eq!(stack(2), [1, 2, 3, 2, 3]);
+        assert_eq!(stack(3), [1, 2, 3, 1, 2, 3]);
+        assert_eq!(stack(0), [1, 2, 3]);
+
+        let unit = single_elf(vec![Push(1), DupBlock(2)]);
+        let mut rt = Runtime::new(&unit);
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::InvalidIndex(1)));
+    }
+
+    #[test]
+    fn read_env() {
+        let src = |var: &str| {
+            let src = format!(
+                "{ECHO}
+                Santa will:
+                    setup echo for elf Echo ()
+                    setup ENV(\"{var}\") -> Echo.1
+                    monitor Echo.1:
+                        receive x
+                        deliver x
+                    ;
+                ;
+                "
+            );
+            make_unit(&src)
+        };
+        let env = |var: &str| match var {
+            "SEED" => Some("72".to_string()),
+            "BAD_SEED" => Some("ho ho ho".to_string()),
+            _ => None,
+        };
+
+        let unit = src("SEED");
+        let mut rt = Runtime::new(&unit);
+        rt.set_env_lookup(Box::new(env));
+        rt.set_output(Out::Buffer(String::new()));
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(output(&rt), "H");
+
+        for var in ["BAD_SEED", "MISSING_SEED"] {
+            let unit = src(var);
+            let mut rt = Runtime::new(&unit);
+            rt.set_env_lookup(Box::new(env));
+            let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+            assert!(matches!(error.code, ECode::Env(_)));
+            assert!(error.to_string().contains(var));
+        }
+    }
+
+    #[test]
+    fn trace_categories() {
+        let unit = make_unit(PIPELINE);
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+        rt.trace_categories(Category::IO.mask());
+
+        let (result, logs) = crate::logger::capture(|| rt.run(RunCommand::RunToEnd).is_ok());
+        assert!(result);
+
+        let traced: Vec<_> = logs.iter().filter(|l| l.starts_with("elf ")).collect();
+        assert!(!traced.is_empty());
+        assert!(traced.iter().all(|l| l.contains("| IO ")), "{traced:#?}");
+    }
+
+    #[test]
+    fn probes() {
+        let unit = make_unit(
+            "
+            workshop probed:
+                floorplan:
+                    e> 01 $one 02 $two Hm
+                ;
+            ;
+
+            Santa will:
+                setup probed for elf Bobo (7)
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+
+        let (result, logs) = crate::logger::capture(|| rt.run(RunCommand::RunToEnd).is_ok());
+        assert!(result);
+
+        let probes: Vec<_> = logs.iter().filter(|l| l.starts_with("probe ")).collect();
+        assert_eq!(
+            probes,
+            [
+                "probe one: elf Bobo [7, 1]",
+                "probe two: elf Bobo [7, 1, 2]"
+            ]
+        );
+    }
+
+    #[test]
+    fn if_empty() {
+        let run = |stack: &str| {
+            let unit = make_unit(&format!(
+                "
+                workshop check:
+                    floorplan:
+                           m> Cn mv
+                        e> ?s    m> O1 Hm
+                           m> Ce m^
+                    ;
+                ;
+
+                Santa will:
+                    setup check for elf Checker ({stack})
+                    monitor Checker.1:
+                        receive x
+                        deliver x
+                    ;
+                ;
+                "
+            ));
+            assert_terminates(&unit, 100)
+        };
+
+        assert_eq!(run(""), "e");
+        assert_eq!(run("1 2"), "n");
+    }
+
+    #[test]
+    fn warn_untouched_initial_stack() {
+        let warnings = |plan: &str| {
+            let unit = make_unit(&format!(
+                "
+                workshop lazy:
+                    floorplan:
+                        {plan}
+                    ;
+                ;
+
+                Santa will:
+                    setup lazy for elf Lazy (1 2 3)
+                ;
+                "
+            ));
+            let mut rt = Runtime::new(&unit);
+            let (result, logs) = crate::logger::capture(|| rt.run(RunCommand::RunToEnd).is_ok());
+            assert!(result);
+            logs.into_iter()
+                .filter(|l| l.contains("without touching"))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            warnings("e> 05 Dr Hm"),
+            ["Elf \"Lazy\" falls asleep without touching its initial stack [1, 2, 3]"]
+        );
+        assert_eq!(
+            warnings("e> D1 Dr Hm"),
+            ["Elf \"Lazy\" falls asleep without touching its initial stack [1]"]
+        );
+        assert!(warnings("e> +_ +_ Hm").is_empty());
+    }
+
+    #[test]
+    fn narrow_cells() {
+        use Instr::*;
+        let run = |bits, program: Vec<Instr>| {
+            let steps = program.len() + 2;
+            let mut unit = single_elf(program);
+            unit.cell_bits = bits;
+            let mut rt = Runtime::new(&unit);
+            rt.run(RunCommand::Step(steps)).unwrap();
+            rt.elves[&0].stack.clone()
+        };
+
+        assert_eq!(run(8, vec![Push(200)]), [-56]);
+        assert_eq!(run(8, vec![Push(200), ArithC(Op::Add, 100)]), [44]);
+        assert_eq!(run(8, vec![Push(-128), Neg]), [-128]);
+        assert_eq!(run(16, vec![Push(300), Push(300), Arith(Op::Mul)]), [24464]);
+        assert_eq!(run(64, vec![Push(200), ArithC(Op::Add, 100)]), [300]);
+        // widths the builder would reject are rounded up
+        assert_eq!(run(0, vec![Push(200)]), [-56]);
+        assert_eq!(run(12, vec![Push(40000)]), [-25536]);
+        assert_eq!(run(100, vec![Push(40000)]), [40000]);
+    }
+
+    #[test]
+    fn if_empty_keeps_stack() {
+        use Instr::*;
+        // nothing to pop on an empty stack, so the check must not pop either
+        assert_eq!(final_stack(vec![IfEmptyPtr(1), Push(2)]), [2]);
+        assert_eq!(final_stack(vec![Push(5), IfEmptyPtr(2), Push(1)]), [5, 1]);
+    }
+
+    #[test]
+    fn peek() {
+        use Instr::*;
+        let unit = single_elf(vec![Peek(1), In(1), Peek(1), Peek(2)]);
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::Step(2)).unwrap(); // santa sets up the elf and leaves
+        rt.send(0, 1, 7);
+
+        rt.run(RunCommand::Step(2)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [7, 7]);
+        rt.run(RunCommand::Step(2)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [7, 7, NO_INPUT, NO_INPUT]);
+    }
+
+    #[test]
+    fn stream_large_file() {
+        let dir = std::env::temp_dir();
+        let src_path = dir.join(format!("santa-stream-{}.in", std::process::id()));
+        let dst_path = dir.join(format!("santa-stream-{}.out", std::process::id()));
+        let content = "ho ho ho, merry christmas!\n".repeat(READ_CHUNK); // ~100 KiB
+        fs::write(&src_path, &content).unwrap();
+
+        let unit = make_unit(&format!(
+            "{ECHO}
+            Santa will:
+                setup echo for elf Copy ()
+                setup FILE({src:?}) -> Copy.1
+                setup Copy.1 -> FILE({dst:?})
+            ;
+            ",
+            src = src_path.to_str().unwrap(),
+            dst = dst_path.to_str().unwrap(),
+        ));
+        let mut rt = Runtime::new(&unit);
+
+        // at most one chunk waits in the pipe at any time
+        let mut max_buffered = 0;
+        while let RunOk::Stepped(_) = rt.run(RunCommand::Step(1000)).unwrap() {
+            if let Some(elf) = rt.elves.get_mut(&0) {
+                let buffered = elf.inputs.get_mut(&1).map_or(0, |p| p.buffered_len());
+                max_buffered = max_buffered.max(buffered);
+            }
+        }
+        drop(rt);
+
+        assert!((1..=READ_CHUNK).contains(&max_buffered), "{max_buffered}");
+        let copied = fs::read_to_string(&dst_path).unwrap();
+        _ = fs::remove_file(&src_path);
+        _ = fs::remove_file(&dst_path);
+        let (got, expected) = (copied.len(), content.len());
+        assert!(copied == content, "copied {got} of {expected} bytes");
+    }
+
+    #[test]
+    fn poll() {
+        use Instr::*;
+        let unit = single_elf(vec![Poll(1), In(1), Poll(1), Poll(1), Poll(2)]);
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::Step(2)).unwrap(); // santa sets up the elf and leaves
+        rt.send(0, 1, 7);
+
+        rt.run(RunCommand::Step(3)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [1, 7, 0]);
+
+        rt.santa_outputs.clear(); // santa hangs up
+        rt.run(RunCommand::Step(2)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [1, 7, 0, -1, -1]);
+    }
+
+    #[test]
+    fn in_len() {
+        use Instr::*;
+        let unit = single_elf(vec![InLen(1), In(1), InLen(1), InLen(2)]);
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::Step(2)).unwrap(); // santa sets up the elf and leaves
+        for value in [7, 8, 9] {
+            rt.send(0, 1, value);
+        }
+
+        rt.run(RunCommand::Step(4)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [3, 7, 2, 0]);
+    }
+
+    #[test]
+    fn repeat_received_count() {
+        let unit = make_unit(
+            "
+            workshop boss:
+                floorplan:
+                    e> 03 O1 Hm
+                ;
+            ;
+
+            workshop worker:
+                floorplan:
+                    e> Hm
+                ;
+            ;
+
+            Santa will:
+                setup boss for elf Boss ()
+                repeat 0:
+                    deliver 33
+                ;
+                monitor Boss.1:
+                    receive n
+                    repeat n:
+                        setup worker for elf ()
+                        deliver 42
+                    ;
+                ;
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(output(&rt), "***");
+        assert_eq!(rt.next_elf_id, 4);
+    }
+
+    /// Producer writes 4 sheets at once, consumer reads them one by one
+    const BURST: &str = "
+        workshop burst:
+            floorplan:
+                e> 01 O1 02 O1 03 O1 04 O1 Hm
+            ;
+        ;
+
+        workshop slow:
+            floorplan:
+                m> e> I1 Dr mv
+                m^          m<
+            ;
+        ;
+
+        Santa will:
+            setup burst for elf Producer ()
+            setup slow for elf Consumer ()
+            setup Producer.1 -> Consumer.1
+        ;
+    ";
+
+    #[test]
+    fn bounded_pipe() {
+        let unit = make_unit(BURST);
+        let mut rt = Runtime::new(&unit);
+        rt.policy = SchedulePolicy::SequentialGreedy;
+        rt.pipe_capacity = Some(2);
+
+        // the producer runs first, but only gets two sheets out before it has to wait
+        let mut most_queued = 0;
+        while let RunOk::Stepped(_) = rt.run(RunCommand::Step(1)).unwrap() {
+            if let Some(consumer) = rt.elves.get_mut(&1) {
+                let queued = consumer.inputs.get_mut(&1).map_or(0, |p| p.buffered_len());
+                most_queued = most_queued.max(queued);
+            }
+        }
+        assert_eq!(most_queued, 2);
+    }
+
+    #[test]
+    fn pipe_high_water() {
+        let unit = make_unit(BURST);
+        let mut rt = Runtime::new(&unit);
+        rt.policy = SchedulePolicy::SequentialGreedy;
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(rt.pipe_high_water(), HashMap::from([((1, 1), 4)]));
+
+        let mut rt = Runtime::new(&unit);
+        rt.pipe_capacity = Some(3);
+        rt.policy = SchedulePolicy::SequentialGreedy;
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(rt.pipe_high_water(), HashMap::from([((1, 1), 3)]));
+    }
+
+    #[test]
+    fn santa_waits() {
+        let unit = make_unit(
+            "
+            Santa will:
+                deliver 65
+                wait 10
+                deliver 66
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+
+        // const, deliver, 10 steps of waiting, const
+        rt.run(RunCommand::Step(13)).unwrap();
+        assert_eq!(output(&rt), "A");
+        rt.run(RunCommand::Step(1)).unwrap();
+        assert_eq!(output(&rt), "AB");
+    }
+
+    #[test]
+    fn santa_waits_zero() {
+        let unit = make_unit(
+            "
+            workshop busy:
+                floorplan:
+                    m> e> mv
+                    m^    m<
+                ;
+            ;
+            Santa will:
+                setup busy for elf Busy ()
+                wait 0
+                deliver 66
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+
+        // setup, wait, const, deliver, the busy elf gets no turn in between
+        for _ in 0..4 {
+            let StepOk::Stepped(step) = rt.step_once().unwrap() else {
+                panic!("santa is not done yet");
+            };
+            assert_eq!(step.elf, None);
+        }
+        assert_eq!(output(&rt), "B");
+    }
+
+    #[test]
+    fn monitor_in_repeat() {
+        let src = format!(
+            "{ECHO}
+            Santa will:
+                setup echo for elf Echo ()
+                repeat 2:
+                    monitor Echo.1:
+                        receive x
+                        deliver x
+                    ;
+                ;
+            ;
+            "
+        );
+        let unit = make_unit(&src);
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(
+            error.code,
+            ECode::DuplicateMonitor { elf: 0, port: 1 }
+        ));
+    }
+
+    #[test]
+    fn receive_without_monitor() {
+        let mut unit = single_elf(vec![Instr::Hammock]);
+        unit.santa.push(SantaCode::Receive(0, 1));
+        let mut rt = Runtime::new(&unit);
+
+        let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+        assert!(matches!(error.code, ECode::NoSuchMonitor { elf: 0, .. }));
+    }
+
+    #[test]
+    fn connect_asleep_elf() {
+        let src = |connect: &str| {
+            format!(
+                "
+            workshop idle:
+                floorplan:
+                    e> Hm
+                ;
+            ;
+            workshop echo:
+                floorplan:
+                    m> e> I1 O1 mv
+                    m^          m<
+                ;
+            ;
+            Santa will:
+                setup idle for elf Early ()
+                setup echo for elf Echo ()
+                wait 5
+                setup {connect}
+            ;
+            "
+            )
+        };
+
+        for connect in ["Early.1 -> Echo.1", "Early.1 -> Early.1"] {
+            let unit = make_unit(&src(connect));
+            let mut rt = Runtime::new(&unit);
+
+            let error = rt.run(RunCommand::RunToEnd).unwrap_err();
+            assert!(matches!(error.code, ECode::ElfGone(0)), "{error}");
+            assert!(error.to_string().contains("connect to elf 0"), "{error}");
+        }
+    }
+
+    #[test]
+    fn deliver_unicode() {
+        let unit = Unit {
+            cell_bits: 64,
+            rooms: vec![],
+            santa: vec![
+                SantaCode::Const(0x1F384),
+                SantaCode::Deliver(0),
+                SantaCode::Const(-1),
+                SantaCode::Deliver(2),
+            ],
+        };
+
+        assert_eq!(assert_terminates(&unit, 10), "\u{1F384}\u{FFFD}");
+    }
+
+    #[test]
+    fn replay_inputs() {
+        let unit = make_unit(PIPELINE);
+        let run = |replay: Option<InputLog>| {
+            let mut rt = Runtime::new(&unit);
+            rt.output = Out::Buffer(String::new());
+            rt.record_inputs(true);
+            if let Some(log) = replay {
+                rt.replay_inputs(log);
+            }
+            rt.run(RunCommand::RunToEnd).unwrap();
+            (output(&rt), rt.input_log().to_vec())
+        };
+
+        let (recorded, log) = run(None);
+        assert_eq!(log, vec![(1, 1, 67), (1, 1, 66), (1, 1, 65)]);
+        assert_eq!(run(Some(log.clone())), (recorded, log));
+
+        // the producer's sheets are not read while replaying
+        let (replayed, _) = run(Some(vec![(1, 1, 'x' as Int)]));
+        assert_eq!(replayed, "y");
+    }
+
+    #[test]
+    fn replay_inspects_log() {
+        use Instr::*;
+        let unit = single_elf(vec![
+            Peek(1),
+            Poll(1),
+            InLen(1),
+            In(1),
+            Poll(1),
+            InLen(1),
+            Peek(1),
+        ]);
+        let mut rt = Runtime::new(&unit);
+        rt.replay_inputs(vec![(0, 1, 7)]);
+        rt.run(RunCommand::Step(2)).unwrap(); // santa sets up the elf and leaves
+        rt.send(0, 1, 8); // not seen, the log replaces the real input
+
+        rt.run(RunCommand::Step(7)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [7, 1, 1, 7, -1, 0, NO_INPUT]);
+    }
+
+    #[test]
+    fn take_output() {
+        let unit = make_unit(PIPELINE);
+        let mut rt = Runtime::new(&unit);
+        assert_eq!(rt.take_output(), None);
+
+        rt.set_output(Out::Buffer(String::new()));
+        rt.run(RunCommand::RunToEnd).unwrap();
+        assert_eq!(rt.take_output().as_deref(), Some("DCB"));
+        assert_eq!(rt.take_output().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn deliver_to_writer() {
+        let unit = make_unit(
+            "
+            Santa will:
+                deliver 72
+                deliver 127876
+            ;
+            ",
+        );
+        let bytes = Arc::new(Mutex::new(Vec::new()));
+        let mut rt = Runtime::new(&unit);
+        rt.set_output(Out::Writer(bytes.clone()));
+        rt.run(RunCommand::RunToEnd).unwrap();
+
+        assert_eq!(*bytes.lock().unwrap(), "H\u{1F384}".as_bytes());
+        assert_eq!(rt.take_output(), None);
+    }
+
+    #[test]
+    fn flush_mode() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let path = std::env::temp_dir().join(format!("santa-flush-{}.out", std::process::id()));
+        let mut unit = single_elf(vec![Instr::Push(65), Instr::Out(1), Instr::Extension(0)]);
+        unit.santa.push(SantaCode::OpenWrite {
+            src: (0, 1),
+            file: path.to_str().unwrap().into(),
+        });
+
+        // what the file holds while the elf is still working
+        let written_during_run = |mode| {
+            let seen = Rc::new(RefCell::new(String::new()));
+            let mut rt = Runtime::new(&unit);
+            rt.flush_mode = mode;
+            let (seen_by_elf, path) = (seen.clone(), path.clone());
+            rt.set_extension_handler(Box::new(move |_, _| {
+                *seen_by_elf.borrow_mut() = fs::read_to_string(&path).unwrap();
+                Ok(())
+            }));
+            rt.run(RunCommand::RunToEnd).unwrap();
+            seen.take()
+        };
+
+        let prompt = written_during_run(FlushMode::Prompt);
+        let batch = written_during_run(FlushMode::Batch);
+        _ = fs::remove_file(&path);
+        assert_eq!(prompt, "A");
+        assert_eq!(batch, "");
+    }
+
+    #[test]
+    fn fuel() {
+        let unit = make_unit(PIPELINE);
+        let mut rt = Runtime::new(&unit);
+        rt.output = Out::Buffer(String::new());
+        rt.add_fuel(10);
+
+        assert!(matches!(rt.run(RunCommand::RunToEnd), Ok(RunOk::OutOfFuel)));
+        assert_eq!(rt.fuel(), Some(0));
+        assert!(matches!(rt.run(RunCommand::RunToEnd), Ok(RunOk::OutOfFuel)));
+
+        rt.add_fuel(1000);
+        assert!(matches!(rt.run(RunCommand::RunToEnd), Ok(RunOk::Done)));
+        assert_eq!(output(&rt), "DCB");
+        assert!(rt.fuel().unwrap() > 0);
+    }
+
+    #[test]
+    fn stack_len() {
+        use Instr::*;
+        assert_eq!(final_stack(vec![StackLen]), [0]);
+        let program = vec![Push(7), Push(8), StackLen, StackLen];
+        assert_eq!(final_stack(program), [7, 8, 2, 3]);
+    }
+
+    #[test]
+    fn clear() {
+        use Instr::*;
+        assert_eq!(final_stack(vec![Clear]), []);
+        let program = vec![Push(1), Push(2), Push(3), Clear, Push(4), StackLen];
+        assert_eq!(final_stack(program), [4, 1]);
+    }
+
+    #[test]
+    fn named_queue() {
+        let src = format!(
+            "{ECHO}
+            workshop emit:
+                floorplan:
+                    mv O1 m<
+                    m> e> ?s
+                          Hm
+                ;
+            ;
+
+            Santa will:
+                setup emit for elf Alice (65 66)
+                setup emit for elf Bob (67)
+                setup echo for elf Echo ()
+                setup QUEUE(\"work\") -> Echo.1
+                setup Alice.1 -> QUEUE(\"work\")
+                setup Bob.1 -> QUEUE(\"work\")
+
+                monitor Echo.1:
+                    receive x
+                    deliver x
+                ;
+            ;
+            "
+        );
+        let unit = make_unit(&src);
+        let mut delivered: Vec<char> = assert_terminates(&unit, 1000).chars().collect();
+        delivered.sort();
+        assert_eq!(delivered, ['A', 'B', 'C']);
+    }
+
+    #[test]
+    fn monitor_two_ports() {
+        let unit = make_unit(
+            "
+            workshop talk:
+                floorplan:
+                    e> CA Oa CB Ob CC Oa Hm
+                ;
+            ;
+
+            Santa will:
+                setup talk for elf Elf ()
+                monitor Elf.a:
+                    receive x
+                    deliver x
+                ;
+                monitor Elf.b:
+                    receive y
+                    deliver y
+                    deliver y
+                ;
+            ;
+            ",
+        );
+        assert_eq!(assert_terminates(&unit, 100), "ABBC");
+    }
+
+    #[test]
+    fn deliver_string() {
+        let unit = make_unit(
+            r#"
+            Santa will:
+                deliver "Merry\n"
+                deliver 33
+                deliver "\tC:\\ \x"
+            ;
+            "#,
+        );
+        assert_eq!(assert_terminates(&unit, 10), "Merry\n!\tC:\\ \\x");
+    }
+
+    #[test]
+    fn elf_utilization() {
+        // santa feeds the producer, with a pause after every sheet in the slow case
+        let consumer_turns = |pause: &str| {
+            let src = format!(
+                "{ECHO}
+                workshop eat:
+                    floorplan:
+                        m> e> I1 Dr mv
+                        m^          m<
+                    ;
+                ;
+                Santa will:
+                    setup echo for elf Producer ()
+                    setup eat for elf Consumer ()
+                    setup Producer.1 -> Consumer.1
+                    repeat 3:
+                        send 65 to Producer.1
+                        {pause}
+                    ;
+                ;"
+            );
+            let unit = make_unit(&src);
+            let mut rt = Runtime::new(&unit);
+            rt.run(RunCommand::RunToEnd).unwrap();
+            rt.elf_utilization()[&1]
+        };
+
+        let (fast_active, fast_idle) = consumer_turns("");
+        let (slow_active, slow_idle) = consumer_turns("wait 20");
+        assert_eq!(fast_active, slow_active);
+        assert!(fast_idle < fast_active, "{fast_idle} idle turns");
+        assert!(slow_idle > 5 * slow_active, "{slow_idle} idle turns");
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let path = std::env::temp_dir().join(format!("santa-trace-{}.json", std::process::id()));
+        let unit = make_unit(PIPELINE);
+        let mut rt = Runtime::new(&unit);
+        rt.set_output(Out::Buffer(String::new()));
+        rt.enable_chrome_trace(&path).unwrap();
+        rt.run(RunCommand::RunToEnd).unwrap();
+        drop(rt);
+
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = trace.lines().collect();
+        assert_eq!(lines.first(), Some(&"["));
+        assert_eq!(lines.last(), Some(&"]"));
+        assert_eq!(
+            lines[1..4],
+            [
+                r#"{"ph":"X","name":"IfEmptyPtr(3)","pid":0,"tid":0,"ts":8,"dur":1},"#,
+                r#"{"ph":"X","name":"Out(1)","pid":0,"tid":0,"ts":9,"dur":1},"#,
+                r#"{"ph":"X","name":"In(1)","pid":1,"tid":0,"ts":10,"dur":1},"#,
+            ]
+        );
+        for event in &lines[1..lines.len() - 1] {
+            let event = event.trim_end_matches(',');
+            assert!(event.starts_with(r#"{"ph":"X","name":""#), "{event}");
+            assert!(event.ends_with(r#","dur":1}"#), "{event}");
+        }
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("Push(65)"), r#""Push(65)""#);
+        assert_eq!(json_string("\"a\\b\""), r#""\"a\\b\"""#);
+        assert_eq!(json_string("\u{1b}[0m\n"), r#""\u001b[0m\u000a""#);
+        assert_eq!(json_string("\u{1F384}"), "\"\u{1F384}\"");
+    }
+
+    #[test]
+    fn tracer() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let unit = single_elf(vec![Instr::Push(2), Instr::Push(3), Instr::Arith(Op::Add)]);
+        let mut rt = Runtime::new(&unit);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = events.clone();
+        rt.set_tracer(Box::new(move |e| sink.borrow_mut().push(e)));
+        rt.run(RunCommand::Step(5)).unwrap();
+
+        let events = events.borrow();
+        let steps: Vec<_> = events
+            .iter()
+            .map(|e| match &e.code {
+                TraceCode::Elf(code) => (e.elf, e.ip, format!("{code:?}"), e.top),
+                TraceCode::Santa(code) => (e.elf, e.ip, format!("{code:?}"), None),
+            })
+            .collect();
+        // santa sets up the elf, leaving is not a step of his code
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[0].0, None);
+        assert_eq!(
+            steps[1..],
+            [
+                (Some(0), 0, "Push(2)".into(), Some(2)),
+                (Some(0), 1, "Push(3)".into(), Some(3)),
+                (Some(0), 2, "Arith(Add)".into(), Some(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn deliver_if() {
+        let unit = make_unit(
+            "
+            workshop emit:
+                program:
+                    C# O1 #-5 O1 CA O1 #0 O1
+                ;
+            ;
+            Santa will:
+                setup emit for elf Producer ()
+                monitor Producer.1:
+                    receive x
+                    deliver x if x > 0
+                ;
+            ;
+            ",
+        );
+        assert_eq!(assert_terminates(&unit, 100), "#A");
+    }
+
+    #[test]
+    fn deterministic_schedule() {
+        // several elves, a queue and a monitor, everything that could race
+        let unit = make_unit(&format!(
+            "{ECHO}
+            workshop emit:
+                floorplan:
+                    mv O1 m<
+                    m> e> ?s
+                          Hm
+                ;
+            ;
+            Santa will:
+                setup emit for elf A (65 66 67 68)
+                setup emit for elf B (69 70 71 72)
+                setup emit for elf C (73 74 75 76)
+                setup echo for elf X ()
+                setup echo for elf Y ()
+                setup A.1 -> QUEUE(\"q\")
+                setup B.1 -> QUEUE(\"q\")
+                setup C.1 -> X.1
+                setup QUEUE(\"q\") -> Y.1
+                monitor X.1:
+                    receive x
+                    deliver x
+                ;
+                monitor Y.1:
+                    receive y
+                    deliver y
+                ;
+            ;"
+        ));
+        let first = assert_terminates(&unit, 1000);
+        assert_eq!(first.len(), 12);
+        for _ in 0..10 {
+            assert_eq!(assert_terminates(&unit, 1000), first);
+        }
+    }
+
+    #[test]
+    fn quantum() {
+        let unit = make_unit(
+            "
+            workshop busy:
+                floorplan:
+                    m> e> 01 Dr mv
+                    m^          m<
+                ;
+            ;
+            workshop hello:
+                program:
+                    CH O1 Ci O1
+                ;
+            ;
+            Santa will:
+                setup busy for elf Busy ()
+                setup hello for elf Hello ()
+                monitor Hello.1:
+                    receive x
+                    deliver x
+                ;
+            ;
+            ",
+        );
+        let run = |quantum| {
+            let mut rt = Runtime::new(&unit);
+            rt.set_output(Out::Buffer(String::new()));
+            rt.quantum = quantum;
+            let result = rt.run(RunCommand::RunWithLimit(100)).unwrap();
+            assert!(matches!(result, RunOk::LimitReached { .. }), "{result:?}");
+            rt.take_output().unwrap()
+        };
+        assert_eq!(run(None), "");
+        assert_eq!(run(Some(10)), "Hi");
+    }
+
+    #[test]
+    fn memory_report() {
+        let unit = make_unit(
+            "
+            workshop grow:
+                floorplan:
+                    m> e> 01 mv
+                    m^       m<
+                ;
+            ;
+            workshop wait:
+                program:
+                    I1
+                ;
+            ;
+            Santa will:
+                setup grow for elf Grow ()
+                setup wait for elf Wait ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::RunWithLimit(1000)).unwrap();
+
+        let report = rt.memory_report();
+        let sheets = |bytes: usize| bytes / size_of::<Int>();
+        assert_eq!(sheets(report[&1]), 10, "just the sleeve");
+        assert!(sheets(report[&0]) > 100, "{report:?}");
+    }
+
+    #[test]
+    fn step_once() {
+        let unit = single_elf(vec![Instr::Push(65), Instr::Out(1)]);
+        let mut rt = Runtime::new(&unit);
+        let mut steps = Vec::new();
+        while let StepOk::Stepped(step) = rt.step_once().unwrap() {
+            let code = step.code.map(|c| match c {
+                TraceCode::Elf(instr) => format!("{instr:?}"),
+                TraceCode::Santa(code) => format!("{code:?}"),
+            });
+            steps.push((step.elf, step.ip, code, step.event));
+        }
+        let some = |s: &str| Some(s.to_string());
+        let setup = some("SetupElf { name: None, room: 0, entry: 0, init_stack: [] }");
+        assert_eq!(
+            steps,
+            [
+                (None, 0, setup, None),
+                (None, 1, None, Some(Event::Dequeue)),
+                (Some(0), 0, some("Push(65)"), None),
+                (Some(0), 1, some("Out(1)"), None), // nobody listens
+                (Some(0), 2, some("Hammock"), Some(Event::Dequeue)),
+            ]
+        );
+    }
+
+    #[test]
+    fn run_until_deliver() {
+        // counts to 6, multiples of 3 are an F
+        let unit = make_unit(
+            "
+            workshop fizz:
+                floorplan:
+                    .. .. .. .. .. m> D0 C0 +_ O1 mv .. .. .. Hm
+                    e> 01 m> D0 %3 ?= .. .. .. .. m> +1 D0 -7 ?<
+                    .. .. .. .. .. m> CF O1 .. .. m^ .. .. .. mv
+                    .. .. m^ .. .. .. .. .. .. .. .. .. .. .. m<
+                ;
+            ;
+            Santa will:
+                deliver \"go:\"
+                setup fizz for elf Fizz ()
+                monitor Fizz.1:
+                    receive c
+                    deliver c
+                ;
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.set_output(Out::Buffer(String::new()));
+
+        let mut delivered = Vec::new();
+        while let DeliverOk::Delivered(text) = rt.run_until_deliver().unwrap() {
+            delivered.push(text);
+            assert_eq!(output(&rt), delivered.concat());
+        }
+        assert_eq!(delivered, ["go:", "1", "2", "F", "4", "5", "F"]);
+        assert_eq!(rt.run_until_deliver().unwrap(), DeliverOk::Done);
+
+        let text = |s: &str| DeliverOk::Delivered(s.to_string());
+        rt.reset();
+        rt.stop_token().store(true, Ordering::Relaxed);
+        assert_eq!(rt.run_until_deliver().unwrap(), DeliverOk::Interrupted);
+        rt.add_fuel(3);
+        assert_eq!(rt.run_until_deliver().unwrap(), text("go:"));
+        assert_eq!(rt.run_until_deliver().unwrap(), DeliverOk::OutOfFuel);
+        rt.add_fuel(1000);
+        assert_eq!(rt.run_until_deliver().unwrap(), text("1"));
+    }
+
+    #[test]
+    fn feed_self() {
+        use Instr::*;
+        let program = vec![Push(3), Push(4), Feed(1), Feed(1), Push(9), In(1), In(1)];
+        assert_eq!(final_stack(program), [9, 4, 3]);
+    }
+
+    #[test]
+    fn coverage() {
+        let unit = make_unit(
+            "
+            workshop branch:
+                floorplan:
+                    .. .. Hm
+                    .. .. 03
+                    e> 00 ?=
+                    .. .. 02
+                    .. .. Hm
+                ;
+            ;
+            Santa will:
+                setup branch for elf Branch ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::RunToEnd).unwrap();
+
+        let room = &unit.rooms[0];
+        let ips = rt.coverage(0);
+        let covered: HashSet<_> = ips.iter().map(|ip| room.ip_to_tile[ip]).collect();
+        // the elf goes right on zero, the branch up never runs
+        assert_eq!(covered, HashSet::from([(1, 2), (2, 2), (2, 3), (2, 4)]));
+        assert_eq!(room.elf_program.len(), 6);
+        assert!(rt.coverage(1).is_empty());
+    }
+
+    #[test]
+    fn stop_token() {
+        let unit = make_unit(
+            "
+            workshop busy:
+                floorplan:
+                    m> e> 01 Dr mv
+                    m^          m<
+                ;
+            ;
+            Santa will:
+                setup busy for elf Busy ()
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        let stop = rt.stop_token();
+
+        stop.store(true, Ordering::Relaxed);
+        let stopped = rt.run(RunCommand::RunToEnd);
+        assert!(matches!(stopped, Ok(RunOk::Interrupted { steps: 0 })));
+        // the flag is cleared, so the run goes on
+        assert!(matches!(rt.run(RunCommand::Step(5)), Ok(RunOk::Stepped(5))));
+
+        let setter = std::thread::spawn(move || stop.store(true, Ordering::Relaxed));
+        let stopped = rt.run(RunCommand::RunToEnd);
+        setter.join().unwrap();
+        assert!(matches!(stopped, Ok(RunOk::Interrupted { .. })));
+        assert_eq!(rt.elves.len(), 1);
+    }
+
+    #[test]
+    fn loopback_echo() {
+        let unit = make_unit(
+            "
+            workshop echo:
+                floorplan:
+                    e> O1 O1 O1 I1 I1 I1 Hm
+                ;
+            ;
+            Santa will:
+                setup echo for elf Echo (1 2 3)
+                setup Echo.1 -> Echo.1
+            ;
+            ",
+        );
+        let mut rt = Runtime::new(&unit);
+        rt.run(RunCommand::Step(6)).unwrap(); // three constants, setup, connect, leave
+        rt.run(RunCommand::Step(6)).unwrap();
+        assert_eq!(rt.elves[&0].stack, [3, 2, 1]);
+    }
+}
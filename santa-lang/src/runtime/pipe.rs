@@ -1,17 +1,37 @@
 use std::{
     collections::VecDeque,
-    sync::{Arc, Weak, mpsc},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
 };
 
 pub struct InputPipe<T: Clone> {
-    weak_tx: Weak<mpsc::Sender<T>>,
+    weak_link: Weak<Link<T>>,
+    backlog: Arc<Backlog>,
     buffer: VecDeque<T>,
     rx: mpsc::Receiver<T>,
 }
 
 #[derive(Default)]
 pub struct OutputPipe<T: Clone> {
-    to: Vec<Arc<mpsc::Sender<T>>>,
+    to: Vec<Arc<Link<T>>>,
+}
+
+/// Sending end of an input, shared by all outputs connected to it
+struct Link<T> {
+    tx: mpsc::Sender<T>,
+    backlog: Arc<Backlog>,
+}
+
+/// How many values are on the way to an input, and how many it takes
+#[derive(Debug)]
+struct Backlog {
+    queued: AtomicUsize,
+    capacity: AtomicUsize,
+    /// Most values ever queued at once
+    high_water: AtomicUsize,
 }
 
 pub enum InputError {
@@ -21,37 +41,109 @@ pub enum InputError {
     Closed,
 }
 
+pub enum OutputError {
+    /// Some connected input is at its capacity, try again after it reads
+    Full,
+}
+
 impl<T: Clone> InputPipe<T> {
     pub fn new_connected(output: &mut OutputPipe<T>) -> Self {
         let (tx, rx) = mpsc::channel();
 
-        let tx = Arc::new(tx);
-        let weak_tx = Arc::downgrade(&tx);
+        let backlog = Arc::new(Backlog {
+            queued: AtomicUsize::new(0),
+            capacity: AtomicUsize::new(usize::MAX),
+            high_water: AtomicUsize::new(0),
+        });
+        let link = Arc::new(Link {
+            tx,
+            backlog: backlog.clone(),
+        });
+        let weak_link = Arc::downgrade(&link);
 
-        output.to.push(tx);
+        output.to.push(link);
         Self {
-            weak_tx,
+            weak_link,
+            backlog,
             rx,
             buffer: Default::default(),
         }
     }
-    pub fn connect(&self, output: &mut OutputPipe<T>) {
+    /// Input nobody writes to yet, `connect` opens it
+    pub fn new_closed() -> Self {
+        Self::new_connected(&mut OutputPipe::new())
+    }
+    pub fn connect(&mut self, output: &mut OutputPipe<T>) {
         output.connect(self);
     }
+    /// Every writer is gone, start over with a fresh channel. Values that already arrived stay.
+    fn reopen(&mut self) -> Arc<Link<T>> {
+        self.recv_to_buffer();
+        let (tx, rx) = mpsc::channel();
+        let link = Arc::new(Link {
+            tx,
+            backlog: self.backlog.clone(),
+        });
+        self.weak_link = Arc::downgrade(&link);
+        self.rx = rx;
+        link
+    }
+    /// Limit how many values may wait in this pipe, see `OutputPipe::try_write`
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.backlog.capacity.store(capacity, Ordering::Relaxed);
+    }
     /// Write directly to the internal (received) buffer
     pub fn write_direct(&mut self, t: T) {
+        self.backlog.push();
         self.buffer.push_back(t);
     }
+    /// Most values that were ever waiting in this pipe at once
+    pub fn high_water(&self) -> usize {
+        self.backlog.high_water.load(Ordering::Relaxed)
+    }
     pub fn try_read(&mut self) -> Result<T, InputError> {
         self.recv_to_buffer();
-        if let Some(v) = self.buffer.pop_front() {
-            return Ok(v);
-        }
-        match self.rx.try_recv() {
-            Ok(v) => Ok(v),
-            Err(mpsc::TryRecvError::Disconnected) => Err(InputError::Closed),
-            Err(mpsc::TryRecvError::Empty) => Err(InputError::Empty),
+        let read = match self.buffer.pop_front() {
+            Some(v) => Ok(v),
+            None => match self.rx.try_recv() {
+                Ok(v) => Ok(v),
+                Err(mpsc::TryRecvError::Disconnected) => Err(InputError::Closed),
+                Err(mpsc::TryRecvError::Empty) => Err(InputError::Empty),
+            },
+        };
+        if read.is_ok() {
+            self.backlog.queued.fetch_sub(1, Ordering::Relaxed);
         }
+        read
+    }
+
+    /// Is there a value to read right now
+    pub fn has_pending(&mut self) -> bool {
+        self.buffered_len() > 0
+    }
+
+    /// No writer is connected anymore, nothing new will arrive
+    pub fn is_closed(&self) -> bool {
+        self.weak_link.strong_count() == 0
+    }
+
+    /// How many values arrived and were not read yet
+    pub fn buffered_len(&mut self) -> usize {
+        self.recv_to_buffer();
+        self.buffer.len()
+    }
+
+    /// Bytes taken by the values on the way and the buffer they arrive into
+    pub fn footprint(&self) -> usize {
+        let queued = self.backlog.queued.load(Ordering::Relaxed);
+        let in_channel = queued.saturating_sub(self.buffer.len());
+        (self.buffer.capacity() + in_channel) * size_of::<T>()
+    }
+
+    /// Look at the next value without taking it out of the pipe
+    pub fn peek(&mut self) -> Option<&T> {
+        self.recv_to_buffer();
+        self.buffer.front()
     }
 
     fn recv_to_buffer(&mut self) {
@@ -61,29 +153,58 @@ impl<T: Clone> InputPipe<T> {
     }
 }
 
+impl Backlog {
+    fn push(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(queued, Ordering::Relaxed);
+    }
+}
+
+impl<T: Clone> Drop for InputPipe<T> {
+    fn drop(&mut self) {
+        // nobody reads anymore, writers should not wait for it
+        self.backlog.capacity.store(usize::MAX, Ordering::Relaxed);
+    }
+}
+
 impl<T: Clone> OutputPipe<T> {
     pub fn new() -> Self {
         Self { to: vec![] }
     }
-    pub fn connect(&mut self, input: &InputPipe<T>) {
-        match input.weak_tx.upgrade() {
-            Some(tx) => self.to.push(tx),
-            None => {
-                todo!("re-open closed channel");
-            }
-        }
+    pub fn connect(&mut self, input: &mut InputPipe<T>) {
+        let link = match input.weak_link.upgrade() {
+            Some(link) => link,
+            None => input.reopen(),
+        };
+        self.to.push(link);
     }
+    /// Write to every connected input, even above its capacity
     pub fn write(&self, t: T) {
         for to in &self.to {
-            _ = to.send(t.clone());
+            to.backlog.push();
+            _ = to.tx.send(t.clone());
+        }
+    }
+    /// Write only if every connected input has room, so the value is never sent half way
+    pub fn try_write(&self, t: T) -> Result<(), OutputError> {
+        let full = self.to.iter().any(|to| {
+            let backlog = &to.backlog;
+            backlog.queued.load(Ordering::Relaxed) >= backlog.capacity.load(Ordering::Relaxed)
+        });
+        match full {
+            true => Err(OutputError::Full),
+            false => {
+                self.write(t);
+                Ok(())
+            }
         }
     }
 }
 
 impl<T: Clone> std::fmt::Debug for InputPipe<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(ptr) = self.weak_tx.upgrade() {
-            f.debug_tuple("InputPipe").field(&ptr).finish()
+        if let Some(ptr) = self.weak_link.upgrade() {
+            f.debug_tuple("InputPipe").field(&ptr.tx).finish()
         } else {
             write!(f, "InputPipe( closed )")
         }
@@ -92,6 +213,47 @@ impl<T: Clone> std::fmt::Debug for InputPipe<T> {
 
 impl<T: Clone> std::fmt::Debug for OutputPipe<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("OutputPipe").field(&self.to).finish()
+        let to: Vec<_> = self.to.iter().map(|link| &link.tx).collect();
+        f.debug_tuple("OutputPipe").field(&to).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reconnect_closed() {
+        let mut output = OutputPipe::new();
+        let mut input = InputPipe::new_connected(&mut output);
+        output.write(1);
+        drop(output);
+        assert!(input.is_closed());
+
+        let mut output = OutputPipe::new();
+        input.connect(&mut output);
+        assert!(!input.is_closed());
+        output.write(2);
+
+        assert!(matches!(input.try_read(), Ok(1)));
+        assert!(matches!(input.try_read(), Ok(2)));
+        assert!(matches!(input.try_read(), Err(InputError::Empty)));
+        drop(output);
+        assert!(matches!(input.try_read(), Err(InputError::Closed)));
+    }
+
+    #[test]
+    fn reconnect_after_reader_saw_close() {
+        let mut output = OutputPipe::new();
+        let mut input = InputPipe::new_connected(&mut output);
+        drop(output);
+        assert!(matches!(input.try_read(), Err(InputError::Closed)));
+
+        // a new producer takes over the finished one
+        let mut output = OutputPipe::new();
+        input.connect(&mut output);
+        output.write(3);
+        assert!(matches!(input.try_read(), Ok(3)));
+        assert!(matches!(input.try_read(), Err(InputError::Empty)));
     }
 }
@@ -0,0 +1,134 @@
+//! Several units running side by side, each in its own `Runtime`
+
+use super::*;
+
+/// Runs units in turns of `slice` steps until all of them end.
+/// What santa delivers is collected per unit, see `output`.
+#[derive(Debug)]
+pub struct RuntimePool<'u> {
+    runtimes: Vec<Runtime<'u>>,
+    /// Steps a runtime makes before the next one gets its turn
+    pub slice: usize,
+}
+
+impl Default for RuntimePool<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'u> RuntimePool<'u> {
+    pub fn new() -> Self {
+        Self {
+            runtimes: Vec::new(),
+            slice: 100,
+        }
+    }
+
+    /// Returns the index of the unit in the pool, see `output`.
+    pub fn add_unit(&mut self, unit: &'u Unit) -> usize {
+        let mut rt = Runtime::new(unit);
+        rt.set_output(Out::Buffer(String::new()));
+        self.runtimes.push(rt);
+        self.runtimes.len() - 1
+    }
+
+    /// Run until every unit ends. A unit that fails stops, the others go on.
+    /// The errors come with the index of the unit.
+    pub fn run_all(&mut self) -> Result<(), Vec<(usize, Error<'u>)>> {
+        let mut errors = Vec::new();
+        let mut running: Vec<usize> = (0..self.runtimes.len()).collect();
+        while !running.is_empty() {
+            let mut still_running = Vec::with_capacity(running.len());
+            for i in running {
+                match self.runtimes[i].run(RunCommand::Step(self.slice)) {
+                    Ok(RunOk::Stepped(_) | RunOk::Breakpoint) => still_running.push(i),
+                    Ok(_) => {}
+                    Err(e) => errors.push((i, e)),
+                }
+            }
+            running = still_running;
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Text the unit at `index` delivered so far
+    pub fn output(&self, index: usize) -> &str {
+        match &self.runtimes[index].output {
+            Out::Buffer(buf) => buf,
+            Out::Std | Out::Writer(_) => unreachable!("the pool buffers all outputs"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::translate::{TranslationInput, translate};
+
+    fn make_unit(src: &str) -> Unit {
+        let input = TranslationInput::Buffer {
+            name: None,
+            text: src.into(),
+        };
+        translate(vec![input]).unwrap()
+    }
+
+    #[test]
+    fn two_units() {
+        let shout = make_unit(
+            "
+            workshop emit:
+                floorplan:
+                    mv O1 m<
+                    m> e> ?s
+                          Hm
+                ;
+            ;
+            Santa will:
+                setup emit for elf Shout (79 72 79 72)
+                monitor Shout.1:
+                    receive c
+                    deliver c
+                ;
+            ;
+            ",
+        );
+        let hello = make_unit(
+            r#"
+            Santa will:
+                deliver "hello"
+            ;
+            "#,
+        );
+
+        let broken = make_unit(
+            "
+            workshop broken:
+                floorplan:
+                    e> 01 %0 Hm
+                ;
+            ;
+            Santa will: setup broken for elf Broken () ;
+            ",
+        );
+
+        let mut pool = RuntimePool::new();
+        pool.slice = 1;
+        let a = pool.add_unit(&shout);
+        let failing = pool.add_unit(&broken);
+        let b = pool.add_unit(&hello);
+
+        let errors = pool.run_all().unwrap_err();
+        let [(unit, error)] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        assert_eq!(*unit, failing);
+        assert!(matches!(error.code, ECode::DivisionByZero));
+        assert_eq!(pool.output(a), "HOHO");
+        assert_eq!(pool.output(b), "hello");
+    }
+}
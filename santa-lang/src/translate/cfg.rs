@@ -0,0 +1,89 @@
+//! Conditional blocks in source files
+//!
+//! ```text
+//! #[if debug]
+//!     deliver x
+//! #[end]
+//! ```
+//!
+//! The directives are comments to the parser, so this runs on the text before parsing.
+//! Lines in a block whose flag is not set are blanked, which keeps line numbers intact.
+
+use std::collections::HashSet;
+
+use super::{ECode, Loc};
+
+/// Blank out the lines of inactive blocks, reports (location, error) of misplaced directives
+pub(crate) fn strip_inactive(
+    source: &str,
+    flags: &HashSet<String>,
+) -> Result<String, (Loc, ECode)> {
+    let mut out = String::with_capacity(source.len());
+    // (line of the `#[if]`, is the block active)
+    let mut open: Vec<(u32, bool)> = Vec::new();
+
+    for (i, line) in source.split_inclusive('\n').enumerate() {
+        let line_no = i as u32 + 1;
+        let directive = line.trim();
+        let col = (line.len() - line.trim_start().len()) as u32;
+        let loc = Loc::new(line_no, col, directive.len() as u32);
+
+        // directives themselves belong to the surrounding block
+        let mut keep = open.iter().all(|(_, active)| *active);
+        if let Some(flag) = directive
+            .strip_prefix("#[if ")
+            .and_then(|d| d.strip_suffix(']'))
+        {
+            let active = flags.contains(flag.trim());
+            open.push((line_no, active));
+        } else if directive == "#[end]" {
+            if open.pop().is_none() {
+                return Err((loc, ECode::UnbalancedIf));
+            }
+            keep = open.iter().all(|(_, active)| *active);
+        }
+
+        if keep {
+            out += line;
+        } else if line.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+
+    match open.pop() {
+        Some((line, _)) => Err((Loc::new(line, 0, 0), ECode::UnbalancedIf)),
+        None => Ok(out),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "a\n#[if debug]\nb\n  #[if verbose]\nc\n  #[end]\n#[end]\nd\n";
+
+    fn strip(flags: &[&str]) -> String {
+        let flags = flags.iter().map(|f| f.to_string()).collect();
+        strip_inactive(SOURCE, &flags).unwrap()
+    }
+
+    #[test]
+    fn nested_blocks() {
+        assert_eq!(strip(&[]), "a\n#[if debug]\n\n\n\n\n#[end]\nd\n");
+        assert_eq!(
+            strip(&["debug"]),
+            "a\n#[if debug]\nb\n  #[if verbose]\n\n  #[end]\n#[end]\nd\n"
+        );
+        assert_eq!(strip(&["debug", "verbose"]), SOURCE);
+        assert_eq!(strip(&["verbose"]), strip(&[]));
+    }
+
+    #[test]
+    fn unbalanced() {
+        let flags = HashSet::new();
+        let (loc, _) = strip_inactive("a\n#[end]\n", &flags).unwrap_err();
+        assert_eq!(loc.line, 2);
+        let (loc, _) = strip_inactive("#[if x]\na\n", &flags).unwrap_err();
+        assert_eq!(loc.line, 1);
+    }
+}
@@ -0,0 +1,102 @@
+//! Stack effect of a whole elf program, checked against `effect: (a b -- c)` declarations
+//!
+//! Every path from a start to the hammock must agree on how deep the stack is at each
+//! instruction. Loops that grow or shrink the stack, instructions whose effect depends on
+//! the stack and elves that never fall asleep make the effect unknown.
+
+use std::collections::HashMap;
+
+use crate::ir::{ElfLine, Instr};
+
+/// Sheets the program takes from the stack it starts with, and how many it leaves in
+/// their place, or `None` when that depends on the run.
+pub(crate) fn program_effect(program: &[Instr], start: ElfLine) -> Option<(usize, usize)> {
+    // depth relative to the start when the elf reaches each instruction
+    let mut depth_at: HashMap<ElfLine, isize> = HashMap::from([(start, 0)]);
+    let mut todo = vec![start];
+    let mut lowest = 0;
+    let mut end_depth = None;
+
+    while let Some(ip) = todo.pop() {
+        let depth = depth_at[&ip];
+        let code = program.get(ip).copied().unwrap_or(Instr::Hammock);
+        let (takes, puts) = code.stack_effect()?;
+        lowest = lowest.min(depth - takes as isize);
+        let after = depth - takes as isize + puts as isize;
+
+        let next: &[ElfLine] = match code {
+            Instr::Hammock => {
+                if end_depth.is_some_and(|end| end != after) {
+                    return None;
+                }
+                end_depth = Some(after);
+                &[]
+            }
+            Instr::JmpPtr(target) => &[target],
+            Instr::IfPosPtr(target) | Instr::IfNzPtr(target) | Instr::IfEmptyPtr(target) => {
+                &[target, ip + 1]
+            }
+            _ => &[ip + 1],
+        };
+        for &next in next {
+            match depth_at.insert(next, after) {
+                None => todo.push(next),
+                Some(seen) if seen != after => return None,
+                Some(_) => {}
+            }
+        }
+    }
+
+    let takes = -lowest;
+    Some((takes as usize, (takes + end_depth?) as usize))
+}
+
+/// Does a program doing `found` fit a workshop declared to do `declared`
+pub(crate) fn fits(declared: (usize, usize), found: (usize, usize)) -> bool {
+    let net = |(takes, puts): (usize, usize)| puts as isize - takes as isize;
+    found.0 <= declared.0 && net(found) == net(declared)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::Op;
+
+    #[test]
+    fn straight_line() {
+        use Instr::*;
+        let program = [In(1), Arith(Op::Add), Swap(1), Out(1)];
+        assert_eq!(program_effect(&program, 0), Some((2, 1)));
+        assert_eq!(program_effect(&[], 0), Some((0, 0)));
+    }
+
+    #[test]
+    fn branches() {
+        use Instr::*;
+        // both arms take one sheet more than they put back
+        let program = [IfNzPtr(3), Drop, JmpPtr(4), Arith(Op::Add), Hammock];
+        assert_eq!(program_effect(&program, 0), Some((3, 1)));
+        // the arms disagree
+        let program = [IfNzPtr(3), Drop, JmpPtr(4), Nop, Hammock];
+        assert_eq!(program_effect(&program, 0), None);
+    }
+
+    #[test]
+    fn loops() {
+        use Instr::*;
+        // count down to zero, the stack keeps its depth
+        let program = [ArithC(Op::Sub, 1), Dup(0), IfNzPtr(0), Drop];
+        assert_eq!(program_effect(&program, 0), Some((1, 0)));
+        // pushes forever
+        assert_eq!(program_effect(&[Push(1), JmpPtr(0)], 0), None);
+        assert_eq!(program_effect(&[Nop, JmpPtr(0)], 0), None);
+    }
+
+    #[test]
+    fn fits_declaration() {
+        assert!(fits((2, 1), (2, 1)));
+        assert!(fits((2, 1), (1, 0)));
+        assert!(!fits((2, 1), (2, 2)));
+        assert!(!fits((1, 1), (2, 2)));
+    }
+}
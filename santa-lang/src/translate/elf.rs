@@ -1,4 +1,4 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 use crate::{
     ir::{Instr, Op, Room},
@@ -29,6 +29,18 @@ impl ElfState {
         self
     }
 
+    /// Undo `step_fwd`, also when it went past the top or left edge.
+    pub fn step_back(mut self) -> Self {
+        use Direction::*;
+        match self.dir {
+            Up => self.y = self.y.wrapping_add(1),
+            Down => self.y -= 1,
+            Left => self.x = self.x.wrapping_add(1),
+            Right => self.x -= 1,
+        }
+        self
+    }
+
     pub fn with_dir(self, dir: Direction) -> Self {
         Self {
             x: self.x,
@@ -60,6 +72,13 @@ fn xy(w: usize, h: usize) -> impl Iterator<Item = (usize, usize)> {
     (0..w * h).map(move |i| (i % w, i / w))
 }
 
+/// Lay out the floorplan as a program, walking the elf's paths depth first.
+///
+/// A path goes on until a Hammock, a wall, or a tile it already walked in the same direction,
+/// which becomes a `JmpPtr` there. At a conditional tile the program continues with the arm
+/// the branch instruction falls through to, that is right for `?=` and `?<` and left for
+/// `?>` and `?s`. The other arm is laid out once the current path ends, the arm of the
+/// latest conditional first.
 pub fn translate_plan(
     shop_name: &SourceStr,
     plan: (usize, usize, &[Tile<SourceStr>]),
@@ -67,20 +86,44 @@ pub fn translate_plan(
 ) -> Option<Room> {
     let (w, h, tiles) = plan;
 
-    // find start
-    let mut elf_starts = xy(w, h).filter_map(|(x, y)| {
-        tiles[x + y * w]
-            .as_elf_start()
-            .map(|d| ElfState::new(x, y, d))
-    });
-    let Some(elf_start) = elf_starts.next() else {
+    // find starts, an unnamed one goes first, named ones are entries for `setup Shop.name`
+    let mut elf_starts: Vec<(Option<&str>, ElfState)> = xy(w, h)
+        .filter_map(|(x, y)| {
+            let tile = &tiles[x + y * w];
+            let name = tile.text.string.get(2..).filter(|n| !n.is_empty());
+            tile.as_elf_start().map(|d| (name, ElfState::new(x, y, d)))
+        })
+        .collect();
+    if elf_starts.is_empty() {
         errors.push(Error::at(shop_name, ECode::MissingElfStart));
         return None;
-    };
-    if elf_starts.next().is_some() {
+    }
+    let unnamed = elf_starts.iter().filter(|(name, _)| name.is_none()).count();
+    let names: HashSet<_> = elf_starts.iter().filter_map(|(name, _)| *name).collect();
+    if unnamed > 1 || unnamed + names.len() < elf_starts.len() {
         errors.push(Error::at(shop_name, ECode::MultipleElfStarts));
         return None;
     }
+    elf_starts.sort_by_key(|(name, _)| name.is_some());
+
+    // portals come in pairs, map each to its partner
+    let mut portal_tiles = BTreeMap::<char, Vec<(usize, usize)>>::new();
+    for (x, y) in xy(w, h) {
+        if let TileKind::Portal(id) = tiles[x + y * w].kind {
+            portal_tiles.entry(id).or_default().push((x, y));
+        }
+    }
+    let mut portals = HashMap::new();
+    for at in portal_tiles.into_values() {
+        if let [a, b] = at[..] {
+            portals.insert(a, b);
+            portals.insert(b, a);
+            continue;
+        }
+        let (x, y) = at[0];
+        let code = ECode::PortalMismatch(tiles[x + y * w].text.clone(), at.len());
+        errors.push(Error::at(shop_name, code).with_map(render_map(plan, x, y)));
+    }
 
     // emitted code
     let mut emit: Vec<(Instr, ElfState)> = Vec::new();
@@ -88,10 +131,27 @@ pub fn translate_plan(
     // map visited tile to instruction index emitted after that tile
     let mut visited = HashMap::<ElfState, usize>::new();
 
-    // state: elf, and optionally where we came from (to fill in jump target later)
-    let mut bfs = VecDeque::<(ElfState, Option<usize>)>::from([(elf_start, None)]);
+    // paths to lay out, depth first: elf, and optionally where we came from
+    // (to fill in jump target later). The first start is popped first,
+    // so that the elf begins at instruction 0.
+    let mut paths = Vec::<(ElfState, Option<usize>)>::new();
+    paths.extend(elf_starts.iter().rev().map(|(_, elf)| (*elf, None)));
+
+    // how the elf got to each state, to show the path in errors
+    let mut came_from = HashMap::<ElfState, ElfState>::new();
+    let path_to = |came_from: &HashMap<ElfState, ElfState>, mut at: ElfState| {
+        let mut seen = HashSet::new();
+        let mut path = HashSet::new();
+        while let Some(prev) = came_from.get(&at)
+            && seen.insert(*prev)
+        {
+            path.insert((prev.x, prev.y));
+            at = *prev;
+        }
+        path
+    };
 
-    while let Some((elf, from)) = bfs.pop_back() {
+    while let Some((elf, from)) = paths.pop() {
         if let Some(f) = from {
             log::trace!("pop {elf:?}, from={f:?}");
         } else {
@@ -106,25 +166,27 @@ pub fn translate_plan(
 
         if !(elf.x < w && elf.y < h) {
             log::debug!("elf walks into a wall {elf:?}");
-            errors.push(Error::at(shop_name, ECode::ElfWallHit(elf.x, elf.y)));
+            // report the last tile the elf stood on, the one past the edge is no help
+            let ElfState { x, y, dir } = elf.step_back();
+            let path = path_to(&came_from, elf);
+            errors.push(
+                Error::at(shop_name, ECode::ElfWallHit { x, y, dir })
+                    .with_map(render_path(plan, &path, x, y)),
+            );
             continue;
         }
 
         // save current instruction pointer to the tile
         visited.insert(elf, emit.len());
+        let first_pushed = paths.len();
 
         // if we jumped in from somewhere, now is a good time to fill in the target pointer
         // because we are AT the target pointer
         if let Some(from) = from {
             let emit_len = emit.len();
-            match &mut emit[from].0 {
-                Instr::JmpPtr(target)
-                | Instr::IfPosPtr(target)
-                | Instr::IfNzPtr(target)
-                | Instr::IfEmptyPtr(target) => {
-                    *target = emit_len;
-                }
-                _ => panic!("bug: jumped from non-jump instr during translation"),
+            match jump_target(&mut emit[from].0) {
+                Some(target) => *target = emit_len,
+                None => panic!("bug: jumped from non-jump instr during translation"),
             }
         }
 
@@ -140,23 +202,23 @@ pub fn translate_plan(
                 let true_elf = elf.step_right();
                 let false_elf = elf.step_left();
                 next = true_elf; // true now, false branch will be processed later
-                bfs.push_back((false_elf, Some(emit.len()))); // we save "where from" on the stack because
+                paths.push((false_elf, Some(emit.len()))); // we save "where from" on the stack because
                 emit.push((Instr::IfNzPtr(emit.len() + 1), elf)); // we dont know where to jump yet (default to here+1=nop)
             }
             TileKind::IsNeg => {
                 next = elf.step_right();
                 emit.push((Instr::ArithC(Op::Add, 1), elf));
-                bfs.push_back((elf.step_left(), Some(emit.len())));
+                paths.push((elf.step_left(), Some(emit.len())));
                 emit.push((Instr::IfPosPtr(emit.len() + 1), elf));
             }
             TileKind::IsPos => {
                 next = elf.step_left();
-                bfs.push_back((elf.step_right(), Some(emit.len())));
+                paths.push((elf.step_right(), Some(emit.len())));
                 emit.push((Instr::IfPosPtr(emit.len() + 1), elf));
             }
             TileKind::IsEmpty => {
                 next = elf.step_left();
-                bfs.push_back((elf.step_right(), Some(emit.len())));
+                paths.push((elf.step_right(), Some(emit.len())));
                 emit.push((Instr::IfEmptyPtr(emit.len() + 1), elf));
             }
             TileKind::Instr(instr) => {
@@ -165,15 +227,97 @@ pub fn translate_plan(
                     continue;
                 }
             }
+            TileKind::Probe => {
+                let name = tile.text.string.trim_start_matches('$');
+                emit.push((Instr::Probe(crate::ir::intern(name)), elf));
+            }
+            TileKind::Wall => {
+                emit.push((Instr::Hammock, elf));
+                continue;
+            }
+            TileKind::Portal(_) => match portals.get(&(elf.x, elf.y)) {
+                Some(&(x, y)) => next = ElfState::new(x, y, elf.dir).step_fwd(),
+                None => continue, // reported above
+            },
             TileKind::Unknown => {
-                errors.push(Error::at(shop_name, ECode::UnknownTile(tile.text.clone())));
+                let path = path_to(&came_from, elf);
+                errors.push(
+                    Error::at(shop_name, ECode::UnknownTile(tile.text.clone()))
+                        .with_map(render_path(plan, &path, elf.x, elf.y)),
+                );
+                continue; // no point walking past it
             }
         }
 
-        bfs.push_back((next, None));
+        paths.push((next, None));
+        for (pushed, _) in &paths[first_pushed..] {
+            came_from.entry(*pushed).or_insert(elf);
+        }
+    }
+
+    // the elf never walks over tiles off its path, look for broken ones there too
+    let walked: HashSet<_> = visited.keys().map(|elf| (elf.x, elf.y)).collect();
+    let mut unreachable = Vec::new();
+    for (x, y) in xy(w, h).filter(|xy| !walked.contains(xy)) {
+        let tile = &tiles[x + y * w];
+        match tile.kind {
+            // the elf never stands on the portal it comes out of
+            TileKind::Empty | TileKind::Wall | TileKind::Portal(_) => {}
+            TileKind::Unknown => errors.push(
+                Error::at(shop_name, ECode::UnknownTile(tile.text.clone()))
+                    .with_map(render_map(plan, x, y)),
+            ),
+            _ => unreachable.push((x, y)),
+        }
+    }
+    // usually a typo in the moves leading there
+    if let Some(&(x, y)) = unreachable.first() {
+        let at: Vec<_> = unreachable
+            .iter()
+            .map(|(x, y)| format!("x={x} y={y}"))
+            .collect();
+        log::warn!(
+            "Unreachable tiles in workshop {}: {}\n{}",
+            shop_name.string,
+            at.join(", "),
+            render_map(plan, x, y)
+        );
+    }
+
+    // paths crossing on an empty tile are legal, but rarely meant,
+    // walking back the same way or merging into a path is no crossing
+    let mut arrivals = HashMap::<(usize, usize), Vec<Direction>>::new();
+    let mut crossings = BTreeSet::new();
+    for elf in visited.keys() {
+        if tiles[elf.x + elf.y * w].kind != TileKind::Empty {
+            continue;
+        }
+        let dirs = arrivals.entry((elf.x, elf.y)).or_default();
+        let across = |d: &Direction| d.left() == elf.dir || d.right() == elf.dir;
+        if dirs.iter().any(across) {
+            crossings.insert((elf.y, elf.x));
+        }
+        dirs.push(elf.dir);
+    }
+    for (y, x) in crossings {
+        log::warn!(
+            "Paths cross in workshop {} at x={x} y={y}\n{}",
+            shop_name.string,
+            render_map(plan, x, y)
+        );
     }
 
+    let mut starts: Vec<usize> = elf_starts.iter().map(|(_, elf)| visited[elf]).collect();
+    let new_ip = thread_jumps(&mut emit, &starts);
+    starts.iter_mut().for_each(|ip| *ip = new_ip[*ip]);
+    let entries = elf_starts
+        .iter()
+        .zip(starts)
+        .filter_map(|((name, _), ip)| Some((name.as_ref()?.to_string(), ip)))
+        .collect();
+
     Some(Room {
+        entries,
         tiles: tiles
             .into_iter()
             .map(|t| t.clone().convert(&|s| s.string))
@@ -188,6 +332,119 @@ pub fn translate_plan(
     })
 }
 
+/// Where a jump instruction goes
+fn jump_target(instr: &mut Instr) -> Option<&mut usize> {
+    match instr {
+        Instr::JmpPtr(target)
+        | Instr::IfPosPtr(target)
+        | Instr::IfNzPtr(target)
+        | Instr::IfEmptyPtr(target) => Some(target),
+        _ => None,
+    }
+}
+
+/// Clean up the jumps where paths merge: a jump landing on a `JmpPtr` goes straight to
+/// its target, and jumps to the next instruction or code no start reaches are dropped.
+/// Returns the new index of every old one.
+fn thread_jumps(emit: &mut Vec<(Instr, ElfState)>, starts: &[usize]) -> Vec<usize> {
+    let len = emit.len();
+    for i in 0..len {
+        let Some(mut target) = jump_target(&mut emit[i].0).map(|t| *t) else {
+            continue;
+        };
+        // a loop of bare jumps never ends, stop once every one was seen
+        for _ in 0..len {
+            match emit.get(target) {
+                Some((Instr::JmpPtr(next), _)) if *next != target => target = *next,
+                _ => break,
+            }
+        }
+        *jump_target(&mut emit[i].0).unwrap() = target;
+    }
+
+    // follow the code from every start
+    let mut reached = vec![false; len];
+    let mut todo: Vec<usize> = starts.to_vec();
+    while let Some(ip) = todo.pop() {
+        if ip >= len || reached[ip] {
+            continue;
+        }
+        reached[ip] = true;
+        let mut instr = emit[ip].0;
+        todo.extend(jump_target(&mut instr).map(|t| *t));
+        if !matches!(instr, Instr::JmpPtr(_) | Instr::Hammock) {
+            todo.push(ip + 1);
+        }
+    }
+    let keep: Vec<bool> = (0..len)
+        .map(|ip| reached[ip] && !matches!(emit[ip].0, Instr::JmpPtr(t) if t == ip + 1))
+        .collect();
+
+    let mut new_ip = Vec::with_capacity(len + 1);
+    new_ip.push(0);
+    for ip in 0..len {
+        new_ip.push(new_ip[ip] + keep[ip] as usize);
+    }
+    let mut ip = 0;
+    emit.retain(|_| {
+        ip += 1;
+        keep[ip - 1]
+    });
+    for (instr, _) in emit.iter_mut() {
+        if let Some(target) = jump_target(instr) {
+            *target = new_ip[*target];
+        }
+    }
+    new_ip
+}
+
+/// Render the rows of the plan around tile `x`,`y` and underline the tile
+fn render_map(plan: (usize, usize, &[Tile<SourceStr>]), x: usize, y: usize) -> String {
+    render_path(plan, &HashSet::new(), x, y)
+}
+
+/// Like `render_map`, but also underline the tiles on `path` with `~~`,
+/// showing the rows it goes through as well
+fn render_path(
+    plan: (usize, usize, &[Tile<SourceStr>]),
+    path: &HashSet<(usize, usize)>,
+    x: usize,
+    y: usize,
+) -> String {
+    const CONTEXT: usize = 2;
+    let (w, h, tiles) = plan;
+    let rows = path.iter().map(|(_, row)| *row);
+    let top = rows.clone().fold(y.saturating_sub(CONTEXT), usize::min);
+    let bottom = rows.fold(h.min(y + CONTEXT + 1), |b, row| b.max(row + 1));
+
+    let mut out = String::new();
+    for row in top..bottom {
+        let row_tiles = &tiles[row * w..(row + 1) * w];
+        let texts: Vec<&str> = row_tiles.iter().map(|t| &*t.text.string).collect();
+        out += &format!("    {}\n", texts.join(" ").trim_end());
+
+        let marks = mark_row(row_tiles, |col| match (col, row) {
+            tile if tile == (x, y) => '^',
+            tile if path.contains(&tile) => '~',
+            _ => ' ',
+        });
+        if !marks.trim().is_empty() {
+            out += &format!("    {}\n", marks.trim_end());
+        }
+    }
+    out.pop();
+    out
+}
+
+/// Line of marks under a row of tiles, each as wide as the tile, e.g. `^^^^` under `#255`
+fn mark_row(row: &[Tile<SourceStr>], mark: impl Fn(usize) -> char) -> String {
+    let width = |tile: &Tile<SourceStr>| tile.text.string.chars().count();
+    let marks: Vec<String> = (row.iter().enumerate())
+        .map(|(col, tile)| mark(col).to_string().repeat(width(tile)))
+        .collect();
+    marks.join(" ")
+}
+
 impl<S> Tile<S> {
     fn as_elf_start(&self) -> Option<Direction> {
         match &self.kind {
@@ -200,10 +457,12 @@ impl<S> Tile<S> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::parse::parse_plan;
+    use crate::ir::{Int, SantaCode, Unit};
+    use crate::parse::{ShopBlock, parse_plan};
+    use crate::runtime::{Runtime, StepOk};
     use crate::translate::{Loc, loc::LineMap};
 
-    fn check_program(tiles: &str, expect_program: &[Instr]) {
+    fn make_plan(tiles: &str) -> (SourceStr, ShopBlock<SourceStr>) {
         crate::logger::init(log::LevelFilter::Trace);
         let shop_name = SourceStr {
             source_name: "test_file".into(),
@@ -218,6 +477,11 @@ mod test {
         }
 
         let plan = result.unwrap().convert(&|s| map.map_slice(s));
+        (shop_name, plan)
+    }
+
+    fn check_program(tiles: &str, expect_program: &[Instr]) {
+        let (shop_name, plan) = make_plan(tiles);
         let mut errors = Vec::new();
 
         let program = translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors).unwrap();
@@ -230,6 +494,33 @@ mod test {
         pretty_assertions::assert_eq!(expect_program, &program.elf_program);
     }
 
+    /// Runs the elf in the floorplan and returns its stack right before it falls asleep
+    fn run_plan(tiles: &str, init: &[Int]) -> Vec<Int> {
+        let (shop_name, plan) = make_plan(tiles);
+        let room = translate_plan(&shop_name, plan.as_plan().unwrap(), &mut Vec::new());
+        let mut santa: Vec<_> = init.iter().map(|v| SantaCode::Const(*v)).collect();
+        santa.push(SantaCode::SetupElf {
+            name: None,
+            room: 0,
+            entry: 0,
+            init_stack: (0..init.len()).collect(),
+        });
+        let unit = Unit {
+            rooms: vec![room.unwrap()],
+            santa,
+            cell_bits: 64,
+        };
+
+        let mut rt = Runtime::new(&unit);
+        let mut stack = Vec::new();
+        while let StepOk::Stepped(_) = rt.step_once().unwrap() {
+            if let Some(elf) = rt.elf(0) {
+                stack = elf.stack().to_vec();
+            }
+        }
+        stack
+    }
+
     use Instr::*;
 
     #[test]
@@ -287,15 +578,28 @@ mod test {
     }
 
     #[test]
-    fn translate_loop_nested() {
+    fn translate_if_empty() {
         check_program(
             "
+               m> 02 mv
+            e> ?s    m> Hm
+               m> 01 m^
+            ",
+            &[IfEmptyPtr(3), Push(2), Hammock, Push(1), JmpPtr(2)],
+        );
+    }
+
+    #[test]
+    fn translate_loop_nested() {
+        let nested = "
                mv    S1 -1 m<
                      m>       Hm
             e> m> D1 ?>    S1
                      m> D0 ?>
                      m^ -1 m<
-            ",
+            ";
+        check_program(
+            nested,
             &[
                 Dup(1),
                 IfPosPtr(3),
@@ -310,5 +614,364 @@ mod test {
                 JmpPtr(3),
             ],
         );
+        // the inner loop counts the top down to zero, then the outer one the sheet below
+        assert_eq!(run_plan(nested, &[2, 3]), [0, 0]);
+    }
+
+    #[test]
+    fn translate_tuck() {
+        check_program(
+            "
+            e> 01 T2 Hm
+            ",
+            &[Push(1), Tuck(2), Hammock],
+        );
+    }
+
+    #[test]
+    fn translate_stack_len() {
+        check_program(
+            "
+            e> 01 !s mv
+               Hm !s m<
+            ",
+            &[Push(1), StackLen, StackLen, Hammock],
+        );
+    }
+
+    #[test]
+    fn unknown_tile_map() {
+        let (shop_name, mut plan) = make_plan(
+            "
+            e> 01 mv
+            Hm    m<
+            ",
+        );
+        if let ShopBlock::Plan { map, .. } = &mut plan {
+            map[2].kind = TileKind::Unknown;
+        }
+        let mut errors = Vec::new();
+        translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors);
+
+        assert_eq!(errors.len(), 1);
+        let message = errors[0].to_string();
+        assert!(message.starts_with("Unknown tile 'mv'"), "{message}");
+        assert!(
+            message.ends_with("    e> 01 mv\n    ~~ ~~ ^^\n    Hm    m<"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn unknown_tile_after_wide_tile() {
+        let (shop_name, mut plan) = make_plan(
+            "
+            e> #255 mv
+            Hm    m<
+            ",
+        );
+        if let ShopBlock::Plan { map, .. } = &mut plan {
+            map[2].kind = TileKind::Unknown;
+        }
+        let mut errors = Vec::new();
+        translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors);
+
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        let message = error.to_string();
+        let map = "    e> #255 mv\n    ~~ ~~~~ ^^\n    Hm    m<";
+        assert!(message.ends_with(map), "{message}");
+    }
+
+    #[test]
+    fn disassemble() {
+        let (shop_name, plan) = make_plan(
+            "
+            mv O1 m<
+            m> e> ?s
+                  Hm
+            ",
+        );
+        let room = translate_plan(&shop_name, plan.as_plan().unwrap(), &mut Vec::new()).unwrap();
+        let expect = "\
+L0:
+   0  IfEmpty(L3)         ; (2, 1)
+   1  Out(1)              ; (1, 0)
+   2  Jmp(L0)             ; (1, 1)
+L3:
+   3  Hammock             ; (2, 2)
+";
+        pretty_assertions::assert_eq!(room.disassemble(), expect);
+    }
+
+    #[test]
+    fn unknown_tiles_off_path() {
+        let (shop_name, mut plan) = make_plan(
+            "
+            e> 01 mv
+            Hm 02 m<
+            03 .. ..
+            ",
+        );
+        // the first on the path, the others off it
+        if let ShopBlock::Plan { map, .. } = &mut plan {
+            for i in [2, 4, 6] {
+                map[i].kind = TileKind::Unknown;
+            }
+        }
+        let mut errors = Vec::new();
+        translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors);
+
+        let mut unknown: Vec<_> = (errors.iter())
+            .map(|e| match &e.code {
+                ECode::UnknownTile(tile) => tile.string.to_string(),
+                code => panic!("unexpected {code:?}"),
+            })
+            .collect();
+        unknown.sort();
+        assert_eq!(unknown, ["02", "03", "mv"]);
+    }
+
+    #[test]
+    fn crossing_paths() {
+        let crossings = |tiles| {
+            let (shop_name, plan) = make_plan(tiles);
+            let (room, logs) = crate::logger::capture(|| {
+                translate_plan(&shop_name, plan.as_plan().unwrap(), &mut Vec::new())
+            });
+            assert!(room.is_some());
+            logs.into_iter()
+                .filter(|l| l.starts_with("Paths cross"))
+                .collect::<Vec<_>>()
+        };
+
+        let crossed = crossings(
+            "
+            .. mv m<
+            e>    m^
+            .. m> m^
+            ",
+        );
+        assert_eq!(crossed.len(), 1);
+        assert!(
+            crossed[0].starts_with("Paths cross in workshop test_shop at x=1 y=1\n"),
+            "{crossed:?}"
+        );
+
+        let clean = crossings(
+            "
+            m> e> 01 mv
+            m^       m<
+            ",
+        );
+        assert!(clean.is_empty(), "{clean:?}");
+
+        // either spelling of the empty tile
+        let dotted = crossings(
+            "
+            .. mv m<
+            e> .. m^
+            .. m> m^
+            ",
+        );
+        assert_eq!(dotted.len(), 1, "{dotted:?}");
+
+        let walked_back = crossings(
+            "
+            m> e> .. m<
+            ",
+        );
+        assert!(walked_back.is_empty(), "{walked_back:?}");
+
+        let merged = crossings(
+            "
+               m> .. mv
+            e> ?=    ..
+               m> .. m> .. Hm
+            ",
+        );
+        assert!(merged.is_empty(), "{merged:?}");
+    }
+
+    #[test]
+    fn walk_off_top_edge() {
+        let (shop_name, plan) = make_plan(
+            "
+            .. m^ Hm
+            e> m^ ..
+            ",
+        );
+        let mut errors = Vec::new();
+        translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors);
+
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        let dir = Direction::Up;
+        assert!(matches!(error.code, ECode::ElfWallHit { x: 1, y: 0, dir: d } if d == dir));
+        let message = error.to_string();
+        assert!(
+            message.starts_with("elf walks off the top edge from tile 1,0"),
+            "{message}"
+        );
+        // the path up to the edge is underlined
+        let map = "    .. m^ Hm\n       ^^\n    e> m^ ..\n    ~~ ~~";
+        assert_eq!(error.map.as_deref(), Some(map));
+    }
+
+    #[test]
+    fn path_through_wide_tiles() {
+        let (shop_name, plan) = make_plan(
+            "
+            e>Start #255 $probe
+            ",
+        );
+        let mut errors = Vec::new();
+        translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors);
+
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        assert!(matches!(error.code, ECode::ElfWallHit { x: 2, y: 0, .. }));
+        let map = "    e>Start #255 $probe\n    ~~~~~~~ ~~~~ ^^^^^^";
+        assert_eq!(error.map.as_deref(), Some(map));
+    }
+
+    #[test]
+    fn unreachable_tiles() {
+        let unreachable = |tiles| {
+            let (shop_name, plan) = make_plan(tiles);
+            let (room, logs) = crate::logger::capture(|| {
+                translate_plan(&shop_name, plan.as_plan().unwrap(), &mut Vec::new())
+            });
+            assert!(room.is_some());
+            logs.into_iter()
+                .filter(|l| l.starts_with("Unreachable"))
+                .collect::<Vec<_>>()
+        };
+
+        let isolated = unreachable(
+            "
+            e> 01 Hm ..
+            .. .. .. Hm
+            ",
+        );
+        assert_eq!(isolated.len(), 1);
+        let expected = "Unreachable tiles in workshop test_shop: x=3 y=1\n";
+        assert!(isolated[0].starts_with(expected), "{isolated:?}");
+
+        assert!(unreachable("\ne> 01 Hm\n").is_empty());
+    }
+
+    #[test]
+    fn named_starts() {
+        let (shop_name, plan) = make_plan(
+            "
+            e>A 01 Hm
+            e>B 02 Hm
+            ",
+        );
+        let mut errors = Vec::new();
+        let room = translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(room.elf_program, [Push(1), Hammock, Push(2), Hammock]);
+        assert_eq!(
+            room.entries,
+            HashMap::from([("A".to_string(), 0), ("B".to_string(), 2)])
+        );
+    }
+
+    #[test]
+    fn wall_ends_path() {
+        let (shop_name, plan) = make_plan(
+            "
+            [] [] [] []
+            e> 01 02 []
+            [] [] [] []
+            ",
+        );
+        let mut errors = Vec::new();
+        let (room, logs) = crate::logger::capture(|| {
+            translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors).unwrap()
+        });
+
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(room.elf_program, [Push(1), Push(2), Hammock]);
+        // walls off the path are not worth a warning
+        assert!(
+            !logs.iter().any(|l| l.starts_with("Unreachable")),
+            "{logs:?}"
+        );
+    }
+
+    #[test]
+    fn portals() {
+        let translate = |tiles| {
+            let (shop_name, plan) = make_plan(tiles);
+            let mut errors = Vec::new();
+            let room = translate_plan(&shop_name, plan.as_plan().unwrap(), &mut errors);
+            (room, errors)
+        };
+
+        let (room, errors) = translate(
+            "
+            e> 01 @a .. .. .. ..
+            .. 02 .. .. @a 03 Hm
+            ",
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(room.unwrap().elf_program, [Push(1), Push(3), Hammock]);
+
+        let (_, errors) = translate(
+            "
+            e> 01 @a Hm
+            ",
+        );
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        assert!(matches!(error.code, ECode::PortalMismatch(_, 1)));
+    }
+
+    #[test]
+    fn branch_order() {
+        // the zero arms fall through, the other arms follow, the later branch first
+        let branches = "
+               m> 01 mv m> 03 mv
+            e> ?=    m> ?=    m> Hm
+               m> 02 m^ m> 04 m^
+            ";
+        check_program(
+            branches,
+            &[
+                IfNzPtr(7),
+                Push(2),
+                IfNzPtr(5),
+                Push(4),
+                Hammock,
+                Push(3),
+                JmpPtr(4),
+                Push(1),
+                JmpPtr(2),
+            ],
+        );
+        assert_eq!(run_plan(branches, &[7, 0]), [7, 3]);
+        assert_eq!(run_plan(branches, &[7]), [3]);
+    }
+
+    #[test]
+    fn thread_merged_jumps() {
+        // the upper arm joins the lower one on `m>`, which alone would be
+        // [IfNzPtr(3), Push(1), Hammock, JmpPtr(1)]
+        let merge = "
+               m> .. mv
+            e> ?=    m> 01 Hm
+               m> .. m^
+            ";
+        check_program(merge, &[IfNzPtr(1), Push(1), Hammock]);
+        assert_eq!(run_plan(merge, &[0]), [1]);
+        assert_eq!(run_plan(merge, &[5]), [1]);
     }
 }
@@ -24,7 +24,8 @@ impl Identifiers {
             Some((existing, _)) => Err(super::Error {
                 source_name: ident.source_name.clone(),
                 loc: Some(ident.loc.clone()),
-                code: super::ECode::IdentifierConflict(existing.clone()),
+                code: super::ECode::IdentifierConflict(ident.clone(), Box::new(existing.clone())),
+                map: None,
             }),
         }
     }
@@ -37,8 +38,16 @@ impl Identifiers {
             None => Err(super::Error {
                 source_name: ident.source_name.clone(),
                 loc: Some(ident.loc.clone()),
-                code: super::ECode::UnknownIdentifier(ident.string.clone()),
+                code: super::ECode::UnknownIdentifier(ident.string.clone(), self.names()),
+                map: None,
             }),
         }
     }
+
+    /// Everything defined so far, sorted
+    fn names(&self) -> Vec<Arc<str>> {
+        let mut names: Vec<_> = self.data.keys().map(|k| k.string.clone()).collect();
+        names.sort();
+        names
+    }
 }
@@ -113,6 +113,7 @@ impl<'i> LineMap<'i> {
             source_name: self.source_name.clone(),
             loc: Some(self.slice_loc(sub)),
             code,
+            map: None,
         }
     }
 
@@ -121,6 +122,7 @@ impl<'i> LineMap<'i> {
             source_name: self.source_name.clone(),
             loc: Some(loc),
             code,
+            map: None,
         }
     }
 }
@@ -5,14 +5,21 @@
 //! - elf program linearization
 
 use peg::{error::ParseError, str::LineCol};
-use std::{collections::HashMap, fmt, fs, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::PathBuf,
+    sync::Arc,
+};
 
 use crate::RecoverResult;
 use crate::ir::{Instr, Room, SantaCode, Unit, to_port};
-use crate::parse::{Expr, ShopBlock, Tile, ToDo, TranslationUnit};
+use crate::parse::{Connection, Direction, Expr, ShopBlock, Tile, ToDo, TranslationUnit};
 use crate::translate::ident::Identifiers;
 use loc::{LineMap, SourceStr};
 
+mod cfg;
+mod effect;
 mod elf;
 mod ident;
 mod loc;
@@ -25,32 +32,80 @@ pub enum TranslationInput {
     Buffer { name: Option<String>, text: String },
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Flags enabling `#[if flag]` blocks
+    pub flags: HashSet<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Error {
     pub source_name: Arc<str>,
     pub loc: Option<Loc>,
     pub code: ECode,
+    /// Rendered floorplan around the offending tile
+    pub map: Option<Arc<str>>,
 }
 #[derive(Debug, Clone)]
 pub enum ECode {
     Io(Arc<std::io::Error>),
     Parse(peg::error::ExpectedSet),
     DuplicateShop(SourceStr),
+    /// Workshop has neither a floorplan nor a program block
     MissingPlan,
     MultiplePlans,
     MultiplePrograms,
+    /// Workshop has both a floorplan and a program block
+    PlanAndProgram,
     MissingElfStart,
+    /// More than one unnamed start tile, or two with the same name
     MultipleElfStarts,
+    /// `setup Shop.name` where the workshop has no start tile with the name
+    UnknownEntry(SourceStr),
     UnknownTile(SourceStr),
-    ElfWallHit(usize, usize),
-    IdentifierConflict(SourceStr),
-    UnknownIdentifier(Arc<str>),
+    /// Elf walks off the grid from the tile `x`,`y` going `dir`
+    ElfWallHit {
+        x: usize,
+        y: usize,
+        dir: Direction,
+    },
+    /// The redefinition and the first definition, boxed to keep `Error` small
+    IdentifierConflict(SourceStr, Box<SourceStr>),
+    /// Identifier that is not defined, and the ones that are
+    UnknownIdentifier(Arc<str>, Vec<Arc<str>>),
+    /// `#[if]` without `#[end]` or the other way around
+    UnbalancedIf,
+    /// `receive` or `send` without a port outside of a monitor block
+    OutsideMonitor,
+    /// `receive .. from` a port that no monitor block watches
+    UnmonitoredReceive(SourceStr, char),
+    /// Second `monitor` block on the same port
+    DuplicateMonitor(SourceStr, char),
+    /// `setup a -> b` between two kinds of connections that cannot be joined, e.g. `QUEUE -> FILE`
+    UnsupportedConnection {
+        src: &'static str,
+        dst: &'static str,
+    },
+    /// Portal tile without exactly one partner, and how many tiles share its character
+    PortalMismatch(SourceStr, usize),
+    /// The program does not do what the workshop's `effect:` says, as (takes, puts)
+    StackEffectMismatch {
+        declared: (usize, usize),
+        found: (usize, usize),
+    },
 }
 
 pub fn translate(inputs: Vec<TranslationInput>) -> Result<Unit, Vec<Error>> {
+    translate_with(inputs, &Options::default())
+}
+
+pub fn translate_with(
+    inputs: Vec<TranslationInput>,
+    options: &Options,
+) -> Result<Unit, Vec<Error>> {
     let mut errors = Vec::new();
 
-    let unit = read_into_unit(inputs, &mut errors);
+    let unit = read_into_unit(inputs, options, &mut errors);
     if errors.is_empty() == false {
         return Err(errors);
     }
@@ -62,42 +117,102 @@ pub fn translate(inputs: Vec<TranslationInput>) -> Result<Unit, Vec<Error>> {
         _ => {}
     });
 
+    // santa can only receive from ports he monitors, each port by one monitor
+    let mut monitored = HashSet::new();
+    walk_todos(&unit.todos, &mut |td| {
+        if let ToDo::Monitor { target, .. } = td
+            && !monitored.insert((target.0.string.clone(), target.1))
+        {
+            let code = ECode::DuplicateMonitor(target.0.clone(), target.1);
+            errors.push(Error::at(&target.0, code));
+        }
+    });
+    walk_todos(&unit.todos, &mut |td| {
+        if let ToDo::Receive { src: Some(src), .. } = td
+            && !monitored.contains(&(src.0.string.clone(), src.1))
+        {
+            let code = ECode::UnmonitoredReceive(src.0.clone(), src.1);
+            errors.push(Error::at(&src.0, code));
+        }
+    });
+
     // translate
-    let mut rooms = Vec::new();
+    let mut shop_rooms = Vec::new();
     let mut scode = Vec::new();
     let mut identifiers = Identifiers::new();
 
     for (sh_name, sh) in unit.workshops {
         let mut plans = sh.blocks.iter().filter_map(|blk| blk.as_plan());
+        let mut programs = sh.blocks.iter().filter_map(|blk| blk.as_program());
 
-        let Some(plan) = plans.next() else {
-            errors.push(Error::at(&sh_name, ECode::MissingPlan));
-            continue;
+        let room_opt = match (plans.next(), programs.next()) {
+            (None, None) => {
+                errors.push(Error::at(&sh_name, ECode::MissingPlan));
+                continue;
+            }
+            (Some(_), Some(_)) => {
+                errors.push(Error::at(&sh_name, ECode::PlanAndProgram));
+                continue;
+            }
+            (Some(plan), None) => elf::translate_plan(&sh_name, plan, &mut errors),
+            (None, Some(program)) => Some(Room::from_program(program.to_vec())),
         };
         if plans.next().is_some() {
             errors.push(Error::at(&sh_name, ECode::MultiplePlans));
         }
+        if programs.next().is_some() {
+            errors.push(Error::at(&sh_name, ECode::MultiplePrograms));
+        }
 
-        let room_opt = elf::translate_plan(&sh_name, plan, &mut errors);
+        if let Some(room) = &room_opt {
+            check_effects(&sh_name, &sh.blocks, room, &mut errors);
+        }
         if let Some(room) = room_opt {
-            identifiers.define(&sh_name, rooms.len());
-            rooms.push(room);
+            shop_rooms.push((sh_name, room));
         }
     }
 
-    emit_todos(&unit.todos, &mut scode, &mut identifiers, &mut errors, None);
+    // workshops with the same floorplan share a room, a different layout of the same program
+    // keeps its own so that errors and coverage point at the right tiles
+    let mut rooms: Vec<Room> = Vec::new();
+    for (sh_name, room) in shop_rooms {
+        let existing = rooms.iter().position(|r| {
+            r.elf_program == room.elf_program
+                && r.entries == room.entries
+                && r.size == room.size
+                && r.tiles == room.tiles
+        });
+        let room_id = existing.unwrap_or_else(|| {
+            rooms.push(room);
+            rooms.len() - 1
+        });
+        identifiers
+            .define(&sh_name, room_id)
+            .recover((), &mut errors);
+    }
+
+    emit_todos(
+        &unit.todos,
+        &rooms,
+        &mut scode,
+        &mut identifiers,
+        &mut errors,
+        None,
+    );
 
     match errors.is_empty() {
         false => Err(errors),
         true => Ok(Unit {
             rooms,
             santa: scode,
+            cell_bits: 64,
         }),
     }
 }
 
 fn emit_todos(
     todos: &[ToDo<SourceStr>],
+    rooms: &[Room],
     scode: &mut Vec<SantaCode>,
     identifiers: &mut Identifiers,
     errors: &mut Vec<Error>,
@@ -105,24 +220,51 @@ fn emit_todos(
 ) {
     for td in todos {
         match td {
-            ToDo::SetupElf { shop, name, stack } => {
-                if let Some(n) = &name {
-                    identifiers.define(&n, scode.len());
-                }
+            ToDo::SetupElf {
+                shop,
+                entry,
+                name,
+                stack,
+            } => {
                 let mut init_stack = Vec::new();
+                let mut unresolved = false;
                 for expr in stack {
                     let line = match expr {
                         Expr::Number(constant) => {
                             scode.push(SantaCode::Const(*constant));
                             scode.len() - 1
                         },
-                        Expr::Var(id) => identifiers.get(id).recover(0, errors),
+                        Expr::Var(id) => identifiers.get(id).unwrap_or_else(|e| {
+                            errors.push(e);
+                            unresolved = true;
+                            0
+                        }),
                     };
                     init_stack.push(line);
                 }
+                let room = identifiers.get(shop).recover(0, errors);
+                let entry = match entry {
+                    None => 0,
+                    Some(e) => match rooms.get(room).and_then(|r| r.entries.get(&*e.string)) {
+                        Some(ip) => *ip,
+                        None => {
+                            errors.push(Error::at(e, ECode::UnknownEntry(e.clone())));
+                            0
+                        }
+                    },
+                };
+                // an elf with a made up stack would only cause more confusing errors
+                if unresolved {
+                    continue;
+                }
+                // constants are emitted first, the elf lives on the setup line
+                if let Some(n) = &name {
+                    identifiers.define(&n, scode.len());
+                }
                 scode.push(SantaCode::SetupElf {
                     name: name.as_ref().map(|s| s.string.to_string()), // TODO Arc::clone
-                    room: identifiers.get(shop).recover(0, errors),
+                    room,
+                    entry,
                     init_stack,
                 });
             }
@@ -144,6 +286,27 @@ fn emit_todos(
                             dst: (dst_elf, to_port(*dst_port)),
                         });
                     }
+                    (Env(name), Port(dst_id, dst_port)) => {
+                        let dst_elf = identifiers.get(dst_id).recover(0, errors);
+                        scode.push(SantaCode::ReadEnv {
+                            var: name.string.clone(),
+                            dst: (dst_elf, to_port(*dst_port)),
+                        });
+                    }
+                    (Port(src_id, src_port), Queue(name)) => {
+                        let src_elf = identifiers.get(src_id).recover(0, errors);
+                        scode.push(SantaCode::ToQueue {
+                            src: (src_elf, to_port(*src_port)),
+                            queue: name.string.clone(),
+                        });
+                    }
+                    (Queue(name), Port(dst_id, dst_port)) => {
+                        let dst_elf = identifiers.get(dst_id).recover(0, errors);
+                        scode.push(SantaCode::FromQueue {
+                            queue: name.string.clone(),
+                            dst: (dst_elf, to_port(*dst_port)),
+                        });
+                    }
                     (Port(src_id, src_port), File(name)) => {
                         let src_elf = identifiers.get(src_id).recover(0, errors);
                         scode.push(SantaCode::OpenWrite {
@@ -151,7 +314,16 @@ fn emit_todos(
                             file: name.string.clone(),
                         });
                     }
-                    _ => todo!("{src:?} -> {dst:?}"),
+                    (src, dst) => {
+                        let (src_kind, src_token) = connection_kind(src);
+                        let (dst_kind, dst_token) = connection_kind(dst);
+                        let code = ECode::UnsupportedConnection {
+                            src: src_kind,
+                            dst: dst_kind,
+                        };
+                        let token = src_token.or(dst_token).expect("bug: std is never parsed");
+                        errors.push(Error::at(token, code));
+                    }
                 }
             }
             ToDo::Monitor { target, todos } => {
@@ -161,7 +333,7 @@ fn emit_todos(
                     port: (elfid, to_port(target.1)),
                     block_len: 0,
                 });
-                emit_todos(todos, scode, identifiers, errors, Some(block_start));
+                emit_todos(todos, rooms, scode, identifiers, errors, Some(block_start));
                 let block_end = scode.len();
                 scode[block_start] = SantaCode::Monitor {
                     port: (elfid, to_port(target.1)),
@@ -177,7 +349,10 @@ fn emit_todos(
                         };
                         *port
                     }
-                    (None, None) => todo!("error: receive used outside of monitor block"),
+                    (None, None) => {
+                        errors.push(outside_monitor(vars.first()));
+                        (0, 0)
+                    }
                 };
 
                 for v in vars {
@@ -194,7 +369,14 @@ fn emit_todos(
                         };
                         *port
                     }
-                    (None, None) => todo!("error: receive used outside of monitor block"),
+                    (None, None) => {
+                        let var = values.iter().find_map(|v| match v {
+                            Expr::Var(id) => Some(id),
+                            Expr::Number(_) => None,
+                        });
+                        errors.push(outside_monitor(var));
+                        (0, 0)
+                    }
                 };
 
                 for v in values {
@@ -208,22 +390,63 @@ fn emit_todos(
                     scode.push(SantaCode::Send(port.0, port.1, ip));
                 }
             }
-            ToDo::Deliver { e } => {
-                let ip = match e {
+            ToDo::Deliver { e, cond } => {
+                let ip = expr_line(e, scode, identifiers, errors);
+                if let Some(cond) = cond {
+                    let a = expr_line(&cond.left, scode, identifiers, errors);
+                    let b = expr_line(&cond.right, scode, identifiers, errors);
+                    let cmp = cond.cmp.negate();
+                    scode.push(SantaCode::SkipIf { a, cmp, b, len: 1 });
+                }
+                scode.push(SantaCode::Deliver(ip));
+            }
+            ToDo::DeliverStr { text } => {
+                scode.push(SantaCode::DeliverStr(unescape(&text.string).into()));
+            }
+            ToDo::Wait { steps } => scode.push(SantaCode::Wait(*steps)),
+            ToDo::Repeat { count, todos } => {
+                let count = match count {
                     Expr::Number(n) => {
                         scode.push(SantaCode::Const(*n));
                         scode.len() - 1
-                    },
+                    }
                     Expr::Var(v) => identifiers.get(v).recover(0, errors),
                 };
-                scode.push(SantaCode::Deliver(ip));
+                let block_start = scode.len();
+                scode.push(SantaCode::Repeat {
+                    count,
+                    block_len: 0,
+                });
+                emit_todos(todos, rooms, scode, identifiers, errors, parent_monitor);
+                scode.push(SantaCode::EndRepeat(block_start));
+                scode[block_start] = SantaCode::Repeat {
+                    count,
+                    block_len: scode.len() - block_start - 1,
+                };
             }
         }
     }
 }
 
+/// Line with the value of `e`, constants get a line of their own
+fn expr_line(
+    e: &Expr<SourceStr>,
+    scode: &mut Vec<SantaCode>,
+    identifiers: &mut Identifiers,
+    errors: &mut Vec<Error>,
+) -> usize {
+    match e {
+        Expr::Number(n) => {
+            scode.push(SantaCode::Const(*n));
+            scode.len() - 1
+        }
+        Expr::Var(v) => identifiers.get(v).recover(0, errors),
+    }
+}
+
 fn read_into_unit(
     inputs: Vec<TranslationInput>,
+    options: &Options,
     errors: &mut Vec<Error>,
 ) -> TranslationUnit<SourceStr> {
     let mut unit = TranslationUnit::default();
@@ -244,6 +467,7 @@ fn read_into_unit(
                             source_name,
                             loc: None,
                             code: ECode::Io(e.into()),
+                            map: None,
                         });
                         continue;
                     }
@@ -255,6 +479,18 @@ fn read_into_unit(
             }
         }
 
+        let source = match cfg::strip_inactive(&source, &options.flags) {
+            Ok(s) => s,
+            Err((loc, code)) => {
+                errors.push(Error {
+                    source_name,
+                    loc: Some(loc),
+                    code,
+                    map: None,
+                });
+                continue;
+            }
+        };
         let map = LineMap::new(&source_name, &source);
 
         let new_unit = match crate::parse(&source) {
@@ -270,11 +506,63 @@ fn read_into_unit(
     unit
 }
 
+/// Resolve `\n`, `\t` and `\\` in a string literal, other backslashes stay
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => out.push('\n'),
+            ('\\', Some('t')) => out.push('\t'),
+            ('\\', Some('\\')) => out.push('\\'),
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    out
+}
+
+/// Error for a `receive` or `send` that does not know which port to use
+fn outside_monitor(token: Option<&SourceStr>) -> Error {
+    match token {
+        Some(token) => Error::at(token, ECode::OutsideMonitor),
+        None => Error {
+            source_name: "".into(),
+            loc: None,
+            code: ECode::OutsideMonitor,
+            map: None,
+        },
+    }
+}
+
+/// Port as written in the source, digit ports are parsed to their value
+fn port_name(port: char) -> String {
+    match port.is_control() {
+        true => to_port(port).to_string(),
+        false => port.to_string(),
+    }
+}
+
+/// Connection kind as written in the source, and its token to point errors at
+fn connection_kind<S>(connection: &Connection<S>) -> (&'static str, Option<&S>) {
+    match connection {
+        Connection::Port(elf, _) => ("an elf port", Some(elf)),
+        Connection::File(name) => ("FILE", Some(name)),
+        Connection::Env(name) => ("ENV", Some(name)),
+        Connection::Queue(name) => ("QUEUE", Some(name)),
+        Connection::Std => ("std", None),
+    }
+}
+
 fn walk_todos<S>(list: &[ToDo<S>], visit: &mut impl FnMut(&ToDo<S>)) {
     for i in list {
         visit(i);
         match i {
             ToDo::Monitor { todos, .. } => walk_todos(todos, visit),
+            ToDo::Repeat { todos, .. } => walk_todos(todos, visit),
             _ => {}
         }
     }
@@ -305,6 +593,48 @@ impl TranslationUnit<SourceStr> {
     }
 }
 
+/// `(takes, puts)` the way `effect:` blocks write it
+fn effect_text((takes, puts): (usize, usize)) -> String {
+    format!("{takes} -- {puts}")
+}
+
+/// Compare the `effect:` blocks of a workshop with what its program does from every start
+fn check_effects(
+    sh_name: &SourceStr,
+    blocks: &[ShopBlock<SourceStr>],
+    room: &Room,
+    errors: &mut Vec<Error>,
+) {
+    let mut starts: Vec<_> = room.entries.values().copied().collect();
+    starts.push(0);
+    starts.sort();
+    starts.dedup();
+
+    for block in blocks {
+        let &ShopBlock::Effect { takes, puts } = block else {
+            continue;
+        };
+        let declared = (takes, puts);
+        for &start in &starts {
+            match effect::program_effect(&room.elf_program, start) {
+                Some(found) if effect::fits(declared, found) => {}
+                Some(found) => {
+                    let code = ECode::StackEffectMismatch { declared, found };
+                    errors.push(Error::at(sh_name, code));
+                    return;
+                }
+                None => {
+                    log::warn!(
+                        "Stack effect of workshop {} depends on the run, it is not checked",
+                        sh_name.string
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
+
 impl<S> ShopBlock<S> {
     fn as_plan(&self) -> Option<(usize, usize, &[Tile<S>])> {
         match self {
@@ -334,26 +664,70 @@ impl fmt::Display for Error {
                 write!(f, "duplicate shop definition: {}", shop.string)?;
                 locations.push(&shop.loc);
             }
-            ECode::MissingPlan => write!(f, "missing floorplan block")?,
+            ECode::MissingPlan => write!(f, "missing floorplan or program block")?,
             ECode::MultiplePlans => write!(f, "multiple floorplan blocks found")?,
             ECode::MultiplePrograms => write!(f, "multiple program blocks found")?,
+            ECode::PlanAndProgram => write!(f, "both floorplan and program blocks found")?,
             ECode::MissingElfStart => write!(f, "missing elf starting tile")?,
             ECode::MultipleElfStarts => write!(f, "multiple elf starting tiles")?,
+            ECode::UnknownEntry(e) => write!(f, "no elf starting tile named {}", e.string)?,
             ECode::UnknownTile(s) => {
                 write!(f, "Unknown tile '{}'", s.string)?;
                 locations.clear();
                 locations.push(&s.loc);
             }
-            ECode::ElfWallHit(x, y) => write!(f, "elf walks into a wall on tile {x},{y}")?,
-            ECode::IdentifierConflict(existing) => {
-                write!(f, "identifier redefined: {}", existing.display_at())?
+            ECode::ElfWallHit { x, y, dir } => {
+                let edge = match dir {
+                    Direction::Up => "top",
+                    Direction::Down => "bottom",
+                    Direction::Left => "left",
+                    Direction::Right => "right",
+                };
+                write!(f, "elf walks off the {edge} edge from tile {x},{y}")?
+            }
+            ECode::IdentifierConflict(new, existing) => {
+                let first = &existing.loc;
+                write!(f, "identifier redefined here {}", new.display_at())?;
+                write!(f, "; first defined at {}:", existing.source_name)?;
+                write!(f, "{}:{}", first.line, first.col)?
+            }
+            ECode::UnknownIdentifier(id, known) => {
+                write!(f, "unknown identifier \"{id}\"")?;
+                if !known.is_empty() {
+                    write!(f, ", defined so far: {}", known.join(", "))?;
+                }
+            }
+            ECode::UnbalancedIf => write!(f, "unbalanced #[if] and #[end]")?,
+            ECode::OutsideMonitor => {
+                write!(f, "receive or send needs a port outside of a monitor block")?
+            }
+            ECode::UnmonitoredReceive(elf, port) => {
+                let port = port_name(*port);
+                write!(f, "receive from {}.{port}, not monitored", elf.string)?
+            }
+            ECode::DuplicateMonitor(elf, port) => {
+                let port = port_name(*port);
+                write!(f, "port {}.{port} is already monitored", elf.string)?
+            }
+            ECode::UnsupportedConnection { src, dst } => {
+                write!(f, "cannot connect {src} to {dst}")?
+            }
+            ECode::PortalMismatch(tile, count) => {
+                let tile = &tile.string;
+                write!(f, "portal {tile} is on {count} tiles, it needs exactly 2")?
+            }
+            ECode::StackEffectMismatch { declared, found } => {
+                write!(f, "workshop declares ({}), ", effect_text(*declared))?;
+                write!(f, "the program does ({})", effect_text(*found))?
             }
-            ECode::UnknownIdentifier(id) => write!(f, "unknown identifier \"{id}\"")?,
         }
 
         if let Some(loc) = &self.loc {
             write!(f, "\n - {}:{}:{}", self.source_name, loc.line, loc.col)?;
         }
+        if let Some(map) = &self.map {
+            write!(f, "\n{map}")?;
+        }
         Ok(())
     }
 }
@@ -367,6 +741,7 @@ impl Error {
                 len: 1,
             }),
             code: ECode::Parse(e.expected),
+            map: None,
         }
     }
     fn at(token: &SourceStr, code: ECode) -> Self {
@@ -374,6 +749,13 @@ impl Error {
             source_name: token.source_name.clone(),
             loc: Some(token.loc.clone()),
             code,
+            map: None,
+        }
+    }
+    fn with_map(self, map: String) -> Self {
+        Self {
+            map: Some(map.into()),
+            ..self
         }
     }
 }
@@ -381,8 +763,8 @@ impl Error {
 #[cfg(test)]
 mod test {
     use crate::{
-        ir::Unit,
-        translate::{Error, TranslationInput},
+        ir::{Instr, SantaCode, Unit},
+        translate::{ECode, Error, Options, TranslationInput},
     };
 
     fn make_unit(src: &str) -> Result<Unit, Vec<Error>> {
@@ -417,4 +799,331 @@ mod test {
 
         unit.unwrap();
     }
+
+    #[test]
+    fn dedup_identical_rooms() {
+        let unit = make_unit(
+            "
+
+            workshop first:
+                floorplan:
+                    m> e> I1 O1 mv
+                    m^          m<
+                ;
+            ;
+
+            workshop second:
+                floorplan:
+                    m> e> I1 O1 mv
+                    m^          m<
+                ;
+            ;
+
+            workshop other:
+                floorplan:
+                    m> e> I1 O2 mv
+                    m^          m<
+                ;
+            ;
+
+            workshop wide:
+                floorplan:
+                    m> e> I1 O1 .. mv
+                    m^             m<
+                ;
+            ;
+
+            Santa will:
+                setup first for elf A ()
+                setup second for elf B ()
+                setup other for elf C ()
+                setup wide for elf D ()
+            ;
+
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(unit.rooms.len(), 3);
+        let rooms: Vec<_> = unit
+            .santa
+            .iter()
+            .filter_map(|code| match code {
+                SantaCode::SetupElf { room, .. } => Some(*room),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rooms[0], rooms[1]);
+        assert_ne!(rooms[0], rooms[2]);
+        // same program, but its tiles are elsewhere
+        let (first, wide) = (&unit.rooms[rooms[0]], &unit.rooms[rooms[3]]);
+        assert_eq!(first.elf_program, wide.elf_program);
+        assert_ne!(rooms[0], rooms[3]);
+    }
+
+    #[test]
+    fn conditional_block() {
+        let src = "
+            workshop sleep:
+                floorplan:
+                    e> Hm
+                ;
+            ;
+
+            Santa will:
+                setup sleep for elf Sleepy ()
+            #[if debug]
+                deliver 68
+            #[end]
+            ;
+        ";
+        let translate = |flags: &[&str]| {
+            let options = Options {
+                flags: flags.iter().map(|f| f.to_string()).collect(),
+            };
+            let input = TranslationInput::Buffer {
+                name: None,
+                text: src.into(),
+            };
+            super::translate_with(vec![input], &options).unwrap()
+        };
+        let delivers = |unit: &Unit| {
+            let is_deliver = |code: &&SantaCode| matches!(code, SantaCode::Deliver(_));
+            unit.santa.iter().filter(is_deliver).count()
+        };
+
+        assert_eq!(delivers(&translate(&["debug"])), 1);
+        assert_eq!(delivers(&translate(&[])), 0);
+    }
+
+    #[test]
+    fn receive_needs_monitor() {
+        let shop = "
+            workshop echo:
+                floorplan:
+                    e> I1 O1 Hm
+                ;
+            ;
+        ";
+        let error = |santa: &str| {
+            let src = format!("{shop} Santa will: setup echo for elf Echo () {santa} ;");
+            match &make_unit(&src).unwrap_err()[..] {
+                [error] => error.code.clone(),
+                errors => panic!("{errors:?}"),
+            }
+        };
+
+        let unmonitored = error("receive x from Echo.1");
+        assert!(matches!(unmonitored, ECode::UnmonitoredReceive(..)));
+        assert!(matches!(error("receive x"), ECode::OutsideMonitor));
+        assert!(matches!(error("send 1"), ECode::OutsideMonitor));
+    }
+
+    #[test]
+    fn unsupported_connection() {
+        // the message and the column it points at
+        let error = |connect: &str| {
+            let src = format!("Santa will: setup {connect} ;");
+            match &make_unit(&src).unwrap_err()[..] {
+                [error] => (error.to_string(), error.loc.as_ref().unwrap().col),
+                errors => panic!("{errors:?}"),
+            }
+        };
+        let message = |text: &str, col| (format!("{text}\n - anonymous:1:{col}"), col);
+
+        let queues = error(r#"QUEUE("a") -> QUEUE("b")"#);
+        assert_eq!(queues, message("cannot connect QUEUE to QUEUE", 25));
+        let file = error(r#"QUEUE("a") -> FILE("out.txt")"#);
+        assert_eq!(file, message("cannot connect QUEUE to FILE", 25));
+        let env = error(r#"ENV("N") -> QUEUE("a")"#);
+        assert_eq!(env, message("cannot connect ENV to QUEUE", 23));
+    }
+
+    #[test]
+    fn duplicate_monitor() {
+        let errors = make_unit(
+            "
+            workshop echo:
+                floorplan:
+                    e> I1 O1 Hm
+                ;
+            ;
+
+            Santa will:
+                setup echo for elf Echo ()
+                monitor Echo.1: receive x deliver x ;
+                monitor Echo.1: receive y ;
+            ;
+            ",
+        )
+        .unwrap_err();
+
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        assert!(matches!(error.code, ECode::DuplicateMonitor(..)));
+        let message = error.to_string();
+        assert!(message.starts_with("port Echo.1 is already"), "{message}");
+        assert_eq!(error.loc.as_ref().unwrap().line, 11);
+    }
+
+    #[test]
+    fn setup_unknown_stack_var() {
+        let src = "workshop foo:
+                program:
+                    Hm
+                ;
+            ;
+            Santa will:
+                setup foo for elf A (1 undefined_var)
+                deliver A
+            ;";
+        let errors = make_unit(src).unwrap_err();
+
+        let unknown: Vec<_> = errors
+            .iter()
+            .map(|e| match &e.code {
+                ECode::UnknownIdentifier(id, _) => (id.to_string(), e.loc.clone().unwrap()),
+                code => panic!("{code:?}"),
+            })
+            .map(|(id, loc)| (id, loc.line, loc.col))
+            .collect();
+        // the elf is not set up, so `A` is not known either
+        assert_eq!(
+            unknown,
+            [("undefined_var".into(), 7, 39), ("A".into(), 8, 24)]
+        );
+    }
+
+    #[test]
+    fn elf_with_stack_is_its_setup_line() {
+        let src = "workshop w:
+                program:
+                    Hm
+                ;
+            ;
+            Santa will:
+                setup w for elf A (1 2)
+                setup w for elf B ()
+                setup A.1 -> B.1
+            ;";
+        let unit = make_unit(src).unwrap();
+
+        let connect = unit.santa.iter().find_map(|code| match code {
+            SantaCode::Connect { src, dst } => Some((src.0, dst.0)),
+            _ => None,
+        });
+        let (src, dst) = connect.unwrap();
+        let name = |line: usize| match &unit.santa[line] {
+            SantaCode::SetupElf { name, .. } => name.clone(),
+            code => panic!("{code:?}"),
+        };
+        // not the constants pushed for A's stack
+        assert_eq!((name(src), name(dst)), (Some("A".into()), Some("B".into())));
+    }
+
+    #[test]
+    fn program_block() {
+        let unit = |shop: &str| {
+            let santa = "Santa will: setup w for elf () ;";
+            make_unit(&format!("workshop w: {shop} ; {santa}"))
+        };
+        // the santa code does not find the broken workshop either
+        let error = |shop: &str| unit(shop).unwrap_err()[0].code.clone();
+
+        let program = unit("program:\n 01 O1\n ;").unwrap();
+        let expected = vec![Instr::Push(1), Instr::Out(1)];
+        assert_eq!(program.rooms[0].elf_program, expected);
+
+        assert!(matches!(error(""), ECode::MissingPlan));
+        let programs = error("program: ; program: ;");
+        assert!(matches!(programs, ECode::MultiplePrograms));
+        let both = error("floorplan: ; program: ;");
+        assert!(matches!(both, ECode::PlanAndProgram));
+    }
+
+    #[test]
+    fn unknown_identifier_lists_known() {
+        let src = "
+            workshop echo:
+                floorplan:
+                    e> I1 O1 Hm
+                ;
+            ;
+
+            Santa will:
+                setup echo for elf Echo ()
+                monitor Echo.1:
+                    receive (a b)
+                    send (a b c)
+                ;
+            ;
+        ";
+        let errors = make_unit(src).unwrap_err();
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        assert!(matches!(&error.code, ECode::UnknownIdentifier(id, _) if &**id == "c"));
+        let message = error.to_string();
+        let known = "defined so far: Echo, a, b, echo";
+        assert!(message.contains(known), "{message}");
+    }
+
+    #[test]
+    fn stack_effect_mismatch() {
+        let src = |effect| {
+            format!(
+                "
+                workshop add:
+                    effect: {effect}
+                    program:
+                        +_ Hm
+                    ;
+                ;
+                Santa will: setup add for elf Adder (1 2) ;
+                "
+            )
+        };
+        make_unit(&src("(a b -- sum)")).unwrap();
+
+        let errors = make_unit(&src("(a b -- a b)")).unwrap_err();
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        let ECode::StackEffectMismatch { declared, found } = error.code else {
+            panic!("{error}")
+        };
+        assert_eq!((declared, found), ((2, 2), (2, 1)));
+        let message = error.to_string();
+        let expected = "declares (2 -- 2), the program does (2 -- 1)";
+        assert!(message.contains(expected), "{message}");
+    }
+
+    #[test]
+    fn identifier_conflict_both_locations() {
+        let src = "
+            workshop echo:
+                floorplan:
+                    e> I1 O1 Hm
+                ;
+            ;
+
+            Santa will:
+                setup echo for elf Echo ()
+                monitor Echo.1:
+                    receive a
+                    receive a
+                ;
+            ;
+        ";
+        let errors = make_unit(src).unwrap_err();
+        let [error] = &errors[..] else {
+            panic!("{errors:?}")
+        };
+        let message = error.to_string();
+        let redefined = "redefined here \"a\" at anonymous:12:28";
+        assert!(message.contains(redefined), "{message}");
+        let first = "first defined at anonymous:11:28";
+        assert!(message.contains(first), "{message}");
+    }
 }